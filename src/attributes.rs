@@ -0,0 +1,451 @@
+use crate::structs::AttributeInfo;
+
+use binrw::{binrw, BinReaderExt, BinWriterExt};
+
+use std::collections::btree_map::BTreeMap;
+use std::io::{Cursor, Error};
+
+#[binrw]
+/// Exception table entry structure that forms part of a code attribute.
+pub struct ExceptionTableEntry {
+    pub start_pc: u16,
+    pub end_pc: u16,
+    pub handler_pc: u16,
+    pub catch_type: u16,
+}
+
+#[binrw]
+/// JVM class file code attribute structure.
+pub struct CodeAttribute {
+    pub max_stack: u16,
+    pub max_locals: u16,
+    pub code_length: u32,
+    #[br(count = code_length)]
+    pub code: Vec<u8>,
+    pub exception_table_length: u16,
+    #[br(count = exception_table_length)]
+    pub exception_table: Vec<ExceptionTableEntry>,
+    pub attributes_count: u16,
+    #[br(count = attributes_count)]
+    pub attributes: Vec<AttributeInfo>,
+}
+
+impl CodeAttribute {
+    ///
+    /// Encodes the code attribute into a generic attribute structure with the given name index.
+    ///
+    /// Code length, exception table length and attribute count are recomputed from their contents,
+    /// so the bytecode, exception table and nested attributes can be modified freely beforehand.
+    ///
+    pub fn encode(mut self, name_index: u16) -> AttributeInfo {
+        self.code_length = self.code.len() as u32;
+        self.exception_table_length = self.exception_table.len() as u16;
+        self.attributes_count = self.attributes.len() as u16;
+        for attr in &mut self.attributes {
+            attr.attribute_length = attr.info.len() as u32;
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        writer.write_be(&self).unwrap();
+        let info = writer.into_inner();
+
+        AttributeInfo {
+            attribute_name_index: name_index,
+            attribute_length: info.len() as u32,
+            info,
+        }
+    }
+}
+
+#[binrw]
+/// Mapping of a bytecode offset to the source line it was compiled from.
+pub struct LineNumberEntry {
+    pub start_pc: u16,
+    pub line_number: u16,
+}
+
+#[binrw]
+/// Name and type of a local variable within a range of bytecode offsets.
+pub struct LocalVariableEntry {
+    pub start_pc: u16,
+    pub length: u16,
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    pub index: u16,
+}
+
+#[binrw]
+/// Name and access flags of a method parameter.
+pub struct MethodParameter {
+    pub name_index: u16,
+    pub access_flags: u16,
+}
+
+#[binrw]
+/// Table of 16-bit entries preceded by a 16-bit entry count.
+struct Table<T: binrw::BinRead<Args = ()> + binrw::BinWrite<Args = ()> + 'static> {
+    count: u16,
+    #[br(count = count)]
+    entries: Vec<T>,
+}
+
+#[binrw]
+/// Parameter table of a `MethodParameters` attribute, which uses an 8-bit entry count.
+struct ParameterTable {
+    count: u8,
+    #[br(count = count)]
+    entries: Vec<MethodParameter>,
+}
+
+/// Type of a local variable or stack entry in a stack map frame.
+pub enum VerificationType {
+    Top,
+    Integer,
+    Float,
+    Double,
+    Long,
+    Null,
+    UninitializedThis,
+    Object(u16),
+    Uninitialized(u16),
+}
+
+/// Stack map frame, describing the types of locals and stack entries at a bytecode offset.
+pub enum StackMapFrame {
+    Same {
+        offset_delta: u16,
+    },
+    SameLocals1StackItem {
+        offset_delta: u16,
+        stack: VerificationType,
+    },
+    Chop {
+        offset_delta: u16,
+        chopped: u8,
+    },
+    Append {
+        offset_delta: u16,
+        locals: Vec<VerificationType>,
+    },
+    Full {
+        offset_delta: u16,
+        locals: Vec<VerificationType>,
+        stack: Vec<VerificationType>,
+    },
+}
+
+/// Attributes `bake` relies on, whose decoding errors are reported instead of keeping the attribute raw.
+const REQUIRED_ATTRIBUTES: [&str; 2] = ["Code", "LineNumberTable"];
+
+/// Class file attribute, decoded according to its attribute name.
+pub enum Attribute {
+    Code(CodeAttribute),
+    LineNumberTable(Vec<LineNumberEntry>),
+    LocalVariableTable(Vec<LocalVariableEntry>),
+    StackMapTable(Vec<StackMapFrame>),
+    Exceptions(Vec<u16>),
+    ConstantValue(u16),
+    SourceFile(u16),
+    MethodParameters(Vec<MethodParameter>),
+    /// Attribute not decoded by `bake`, given by its name and raw contents.
+    Other(String, Vec<u8>),
+}
+
+fn read_verification_type(reader: &mut Cursor<&Vec<u8>>) -> binrw::BinResult<VerificationType> {
+    let tag: u8 = reader.read_be()?;
+
+    Ok(match tag {
+        0 => VerificationType::Top,
+        1 => VerificationType::Integer,
+        2 => VerificationType::Float,
+        3 => VerificationType::Double,
+        4 => VerificationType::Long,
+        5 => VerificationType::Null,
+        6 => VerificationType::UninitializedThis,
+        7 => VerificationType::Object(reader.read_be()?),
+        8 => VerificationType::Uninitialized(reader.read_be()?),
+        _ => {
+            return Err(binrw::Error::AssertFail {
+                pos: reader.position() - 1,
+                message: format!("invalid verification type tag {}", tag),
+            })
+        }
+    })
+}
+
+fn read_verification_types(
+    reader: &mut Cursor<&Vec<u8>>,
+    count: u16,
+) -> binrw::BinResult<Vec<VerificationType>> {
+    (0..count).map(|_| read_verification_type(reader)).collect()
+}
+
+fn read_stack_map_frame(reader: &mut Cursor<&Vec<u8>>) -> binrw::BinResult<StackMapFrame> {
+    let frame_type: u8 = reader.read_be()?;
+
+    Ok(match frame_type {
+        0..=63 => StackMapFrame::Same {
+            offset_delta: frame_type as u16,
+        },
+        64..=127 => StackMapFrame::SameLocals1StackItem {
+            offset_delta: (frame_type - 64) as u16,
+            stack: read_verification_type(reader)?,
+        },
+        247 => StackMapFrame::SameLocals1StackItem {
+            offset_delta: reader.read_be()?,
+            stack: read_verification_type(reader)?,
+        },
+        248..=250 => StackMapFrame::Chop {
+            offset_delta: reader.read_be()?,
+            chopped: 251 - frame_type,
+        },
+        251 => StackMapFrame::Same {
+            offset_delta: reader.read_be()?,
+        },
+        252..=254 => {
+            let offset_delta = reader.read_be()?;
+            StackMapFrame::Append {
+                offset_delta,
+                locals: read_verification_types(reader, (frame_type - 251) as u16)?,
+            }
+        }
+        255 => {
+            let offset_delta = reader.read_be()?;
+            let locals_count: u16 = reader.read_be()?;
+            let locals = read_verification_types(reader, locals_count)?;
+            let stack_count: u16 = reader.read_be()?;
+            StackMapFrame::Full {
+                offset_delta,
+                locals,
+                stack: read_verification_types(reader, stack_count)?,
+            }
+        }
+        _ => {
+            return Err(binrw::Error::AssertFail {
+                pos: reader.position() - 1,
+                message: format!("reserved stack map frame type {}", frame_type),
+            })
+        }
+    })
+}
+
+fn read_stack_map_table(reader: &mut Cursor<&Vec<u8>>) -> binrw::BinResult<Vec<StackMapFrame>> {
+    let count: u16 = reader.read_be()?;
+    (0..count).map(|_| read_stack_map_frame(reader)).collect()
+}
+
+///
+/// Decodes a generic attribute structure according to its name in the constant pool.
+///
+/// Returns an error if the attribute name is not a UTF-8 constant or the attribute contents are malformed.
+///
+pub fn decode_attribute(
+    attr: &AttributeInfo,
+    utf8_constpool: &BTreeMap<u16, String>,
+) -> Result<Attribute, Error> {
+    let name = utf8_constpool
+        .get(&attr.attribute_name_index)
+        .ok_or_else(|| Error::other("attribute name is not a UTF-8 constant"))?;
+    let mut reader = Cursor::new(&attr.info);
+
+    let attribute = match name.as_str() {
+        "Code" => reader.read_be().map(Attribute::Code),
+        "LineNumberTable" => reader
+            .read_be()
+            .map(|t: Table<LineNumberEntry>| Attribute::LineNumberTable(t.entries)),
+        "LocalVariableTable" => reader
+            .read_be()
+            .map(|t: Table<LocalVariableEntry>| Attribute::LocalVariableTable(t.entries)),
+        "StackMapTable" => read_stack_map_table(&mut reader).map(Attribute::StackMapTable),
+        "Exceptions" => reader
+            .read_be()
+            .map(|t: Table<u16>| Attribute::Exceptions(t.entries)),
+        "ConstantValue" => reader.read_be().map(Attribute::ConstantValue),
+        "SourceFile" => reader.read_be().map(Attribute::SourceFile),
+        "MethodParameters" => reader
+            .read_be()
+            .map(|t: ParameterTable| Attribute::MethodParameters(t.entries)),
+        _ => Ok(Attribute::Other(name.to_string(), attr.info.to_vec())),
+    };
+
+    attribute.map_err(|err| Error::other(format!("malformed {} attribute: {}", name, err)))
+}
+
+///
+/// Decodes a list of generic attribute structures.
+///
+/// Malformed attributes `bake` does not rely on are kept raw as `Attribute::Other`, so they do not
+/// prevent the conversion of the method. Returns the decoded attributes in class file order,
+/// or the first error decoding a `Code` or `LineNumberTable` attribute or an attribute name.
+///
+pub fn decode_attributes(
+    attrs: &[AttributeInfo],
+    utf8_constpool: &BTreeMap<u16, String>,
+) -> Result<Vec<Attribute>, Error> {
+    attrs
+        .iter()
+        .map(|attr| match decode_attribute(attr, utf8_constpool) {
+            Err(err) => match utf8_constpool.get(&attr.attribute_name_index) {
+                Some(name) if !REQUIRED_ATTRIBUTES.contains(&name.as_str()) => {
+                    Ok(Attribute::Other(name.to_string(), attr.info.to_vec()))
+                }
+                _ => Err(err),
+            },
+            attribute => attribute,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 9] = [
+        "Code",
+        "LineNumberTable",
+        "LocalVariableTable",
+        "StackMapTable",
+        "Exceptions",
+        "ConstantValue",
+        "SourceFile",
+        "MethodParameters",
+        "Synthetic",
+    ];
+
+    ///
+    /// Decodes an attribute with the given name and contents, with the attribute names
+    /// at constant pool indices 1 and following.
+    ///
+    fn decode(name: &str, info: &[u8]) -> Result<Attribute, Error> {
+        let (constpool, attr) = attribute(name, info);
+        decode_attribute(&attr, &constpool)
+    }
+
+    fn attribute(name: &str, info: &[u8]) -> (BTreeMap<u16, String>, AttributeInfo) {
+        let constpool: BTreeMap<u16, String> = (1..)
+            .zip(NAMES)
+            .map(|(index, name)| (index, name.to_string()))
+            .collect();
+        let attr = AttributeInfo {
+            attribute_name_index: NAMES.iter().position(|n| *n == name).unwrap() as u16 + 1,
+            attribute_length: info.len() as u32,
+            info: info.to_vec(),
+        };
+        (constpool, attr)
+    }
+
+    #[test]
+    fn code() {
+        let info = [0, 2, 0, 1, 0, 0, 0, 2, 0x04, 0xac, 0, 0, 0, 0];
+        let Ok(Attribute::Code(code)) = decode("Code", &info) else {
+            panic!("expected Code attribute");
+        };
+        assert_eq!((code.max_stack, code.max_locals), (2, 1));
+        assert_eq!(code.code, vec![0x04, 0xac]);
+        assert!(code.exception_table.is_empty() && code.attributes.is_empty());
+        assert!(decode("Code", &info[..9]).is_err());
+    }
+
+    #[test]
+    fn line_number_table() {
+        let info = [0, 2, 0, 0, 0, 5, 0, 4, 0, 6];
+        let Ok(Attribute::LineNumberTable(entries)) = decode("LineNumberTable", &info) else {
+            panic!("expected LineNumberTable attribute");
+        };
+        let lines: Vec<(u16, u16)> = entries
+            .iter()
+            .map(|e| (e.start_pc, e.line_number))
+            .collect();
+        assert_eq!(lines, vec![(0, 5), (4, 6)]);
+    }
+
+    #[test]
+    fn local_variable_table() {
+        let info = [0, 1, 0, 0, 0, 5, 0, 7, 0, 8, 0, 3];
+        let Ok(Attribute::LocalVariableTable(entries)) = decode("LocalVariableTable", &info) else {
+            panic!("expected LocalVariableTable attribute");
+        };
+        let entry = &entries[0];
+        assert_eq!((entry.start_pc, entry.length, entry.index), (0, 5, 3));
+        assert_eq!((entry.name_index, entry.descriptor_index), (7, 8));
+    }
+
+    #[test]
+    fn stack_map_table() {
+        // same, same_locals_1_stack_item with an int, full with an object local and no stack items
+        let info = [0, 3, 3, 66, 1, 255, 0, 10, 0, 1, 7, 0, 9, 0, 0];
+        let Ok(Attribute::StackMapTable(frames)) = decode("StackMapTable", &info) else {
+            panic!("expected StackMapTable attribute");
+        };
+        assert!(matches!(frames[0], StackMapFrame::Same { offset_delta: 3 }));
+        assert!(matches!(
+            frames[1],
+            StackMapFrame::SameLocals1StackItem {
+                offset_delta: 2,
+                stack: VerificationType::Integer
+            }
+        ));
+        let StackMapFrame::Full {
+            offset_delta: 10,
+            locals,
+            stack,
+        } = &frames[2]
+        else {
+            panic!("expected full frame");
+        };
+        assert!(matches!(locals[..], [VerificationType::Object(9)]) && stack.is_empty());
+        // reserved frame type
+        assert!(decode("StackMapTable", &[0, 1, 128]).is_err());
+    }
+
+    #[test]
+    fn exceptions() {
+        let Ok(Attribute::Exceptions(classes)) = decode("Exceptions", &[0, 2, 0, 3, 0, 4]) else {
+            panic!("expected Exceptions attribute");
+        };
+        assert_eq!(classes, vec![3, 4]);
+    }
+
+    #[test]
+    fn constant_value_and_source_file() {
+        assert!(matches!(
+            decode("ConstantValue", &[0, 4]),
+            Ok(Attribute::ConstantValue(4))
+        ));
+        assert!(matches!(
+            decode("SourceFile", &[0, 5]),
+            Ok(Attribute::SourceFile(5))
+        ));
+    }
+
+    #[test]
+    fn method_parameters() {
+        let info = [2, 0, 6, 0, 0x10, 0, 7, 0, 0];
+        let Ok(Attribute::MethodParameters(parameters)) = decode("MethodParameters", &info) else {
+            panic!("expected MethodParameters attribute");
+        };
+        let parameters: Vec<(u16, u16)> = parameters
+            .iter()
+            .map(|p| (p.name_index, p.access_flags))
+            .collect();
+        assert_eq!(parameters, vec![(6, 0x10), (7, 0)]);
+    }
+
+    #[test]
+    fn unused_malformed_attributes() {
+        assert!(matches!(
+            decode("Synthetic", &[]),
+            Ok(Attribute::Other(name, _)) if name == "Synthetic"
+        ));
+
+        // a truncated SourceFile attribute is kept raw, a truncated LineNumberTable is an error
+        let (constpool, source) = attribute("SourceFile", &[0]);
+        assert!(decode_attribute(&source, &constpool).is_err());
+        let attributes = decode_attributes(&[source], &constpool).unwrap();
+        assert!(
+            matches!(&attributes[..], [Attribute::Other(name, info)] if name == "SourceFile" && info == &[0])
+        );
+        let (constpool, lines) = attribute("LineNumberTable", &[0, 1, 0]);
+        assert!(decode_attributes(&[lines], &constpool).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::ir::decode;
+use crate::opcodes::{opmap, Flow, Operand};
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Error;
+use std::str::FromStr;
+
+/// Kind of a control flow edge between two basic blocks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    /// Execution continues with the following block, e.g. if a conditional branch is not taken.
+    Next,
+    /// Conditional branch taken.
+    Taken,
+    /// Unconditional branch.
+    Goto,
+    /// Switch case with the given match value.
+    Case(i32),
+    /// Default target of a switch.
+    Default,
+}
+
+impl fmt::Display for EdgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EdgeKind::Next => write!(f, "next"),
+            EdgeKind::Taken => write!(f, "taken"),
+            EdgeKind::Goto => write!(f, "goto"),
+            EdgeKind::Case(value) => write!(f, "case {}", value),
+            EdgeKind::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Control flow edge to the basic block starting at bytecode offset `target`.
+pub struct Edge {
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+/// Sequence of instructions only entered at its first and only left after its last instruction.
+pub struct BasicBlock {
+    /// Bytecode offset of the first instruction.
+    pub start: usize,
+    /// Bytecode offset following the last instruction.
+    pub end: usize,
+    pub successors: Vec<Edge>,
+}
+
+/// Output format of a control flow graph.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    /// Graphviz DOT.
+    Dot,
+    /// Mermaid flowchart.
+    Mermaid,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            _ => Err(format!(
+                "invalid graph format '{}', expected dot or mermaid",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GraphFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphFormat::Dot => write!(f, "dot"),
+            GraphFormat::Mermaid => write!(f, "mermaid"),
+        }
+    }
+}
+
+fn word(bytes: &[u8], index: usize) -> i32 {
+    let bytes = &bytes[4 * index..4 * index + 4];
+    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+///
+/// Determines the branch targets of the instruction at offset `pc` with the given operands.
+///
+/// Switch operands start with the padding to the next multiple of four, followed by the default offset
+/// and the jump table (`tableswitch`) or the match and offset pairs (`lookupswitch`).
+///
+fn branch_targets(operand: Operand, flow: Flow, pc: usize, operands: &[u8]) -> Vec<Edge> {
+    let edge = |offset: i32, kind: EdgeKind| Edge {
+        target: (pc as i64 + offset as i64) as usize,
+        kind,
+    };
+    let table = || &operands[(4 - (pc + 1) % 4) % 4..];
+
+    match (operand, flow) {
+        (Operand::Branch, _) => {
+            let offset = match *operands {
+                [a, b] => i16::from_be_bytes([a, b]) as i32,
+                [a, b, c, d] => i32::from_be_bytes([a, b, c, d]),
+                _ => return Vec::new(),
+            };
+            let kind = if flow == Flow::Goto {
+                EdgeKind::Goto
+            } else {
+                EdgeKind::Taken
+            };
+            vec![edge(offset, kind)]
+        }
+        (Operand::TableSwitch, _) => {
+            let table = table();
+            let low = word(table, 1);
+            let high = word(table, 2);
+            let mut edges = vec![edge(word(table, 0), EdgeKind::Default)];
+            for (i, value) in (low..=high).enumerate() {
+                edges.push(edge(word(table, 3 + i), EdgeKind::Case(value)));
+            }
+            edges
+        }
+        (Operand::LookupSwitch, _) => {
+            let table = table();
+            let pairs = word(table, 1) as usize;
+            let mut edges = vec![edge(word(table, 0), EdgeKind::Default)];
+            for i in 0..pairs {
+                edges.push(edge(
+                    word(table, 3 + 2 * i),
+                    EdgeKind::Case(word(table, 2 + 2 * i)),
+                ));
+            }
+            edges
+        }
+        _ => Vec::new(),
+    }
+}
+
+///
+/// Splits method bytecode into basic blocks, in bytecode order.
+///
+/// Blocks start at the method entry, at branch and switch targets and after instructions
+/// not continuing with the next instruction. Method calls do not end a block.
+/// Returns an error if the bytecode cannot be decoded.
+///
+pub fn basic_blocks(code: &[u8]) -> Result<Vec<BasicBlock>, Error> {
+    let opmap = opmap();
+    let instrs = decode(code, &[])?;
+
+    let mut leaders: BTreeSet<usize> = BTreeSet::new();
+    leaders.insert(0);
+    let mut exits: Vec<(usize, usize, Vec<Edge>, bool)> = Vec::with_capacity(instrs.len());
+    for instr in &instrs {
+        let op = &opmap[&instr.opcode];
+        let pc = instr.pc.unwrap_or_default() as usize;
+        let next = pc + instr.size();
+        let targets = branch_targets(op.operand, op.flow, pc, &instr.operands);
+
+        leaders.extend(targets.iter().map(|edge| edge.target));
+        if op.flow != Flow::Next && op.flow != Flow::Call {
+            leaders.insert(next);
+        }
+        exits.push((pc, next, targets, op.is_terminator()));
+    }
+
+    let mut blocks: Vec<BasicBlock> = Vec::new();
+    for (pc, next, targets, terminator) in exits {
+        if leaders.contains(&pc) {
+            blocks.push(BasicBlock {
+                start: pc,
+                end: next,
+                successors: Vec::new(),
+            });
+        }
+        let block = blocks.last_mut().unwrap();
+        block.end = next;
+
+        // the last instruction of a block determines its successors
+        if leaders.contains(&next) || next == code.len() {
+            block.successors = targets;
+            if !terminator && next < code.len() {
+                block.successors.push(Edge {
+                    target: next,
+                    kind: EdgeKind::Next,
+                });
+            }
+        }
+    }
+
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start, end and successors of a basic block.
+    type Summary = (usize, usize, Vec<(usize, EdgeKind)>);
+
+    fn summary(blocks: &[BasicBlock]) -> Vec<Summary> {
+        blocks
+            .iter()
+            .map(|block| {
+                let edges = block.successors.iter().map(|e| (e.target, e.kind));
+                (block.start, block.end, edges.collect())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loop_blocks() {
+        // iconst_3; istore_0; loop: iinc 0 -1; iload_0; ifne loop; iload_0; ireturn
+        let code = [
+            0x06, 0x3b, 0x84, 0x00, 0xff, 0x1a, 0x9a, 0xff, 0xfc, 0x1a, 0xac,
+        ];
+        let blocks = basic_blocks(&code).unwrap();
+
+        assert_eq!(
+            summary(&blocks),
+            vec![
+                (0, 2, vec![(2, EdgeKind::Next)]),
+                (2, 9, vec![(2, EdgeKind::Taken), (9, EdgeKind::Next)]),
+                (9, 11, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn branch_blocks() {
+        // iload_0; ifeq else; iconst_1; goto end; else: iconst_2; end: ireturn
+        let code = [0x1a, 0x99, 0x00, 0x07, 0x04, 0xa7, 0x00, 0x04, 0x05, 0xac];
+        let blocks = basic_blocks(&code).unwrap();
+
+        assert_eq!(
+            summary(&blocks),
+            vec![
+                (0, 4, vec![(8, EdgeKind::Taken), (4, EdgeKind::Next)]),
+                (4, 8, vec![(9, EdgeKind::Goto)]),
+                (8, 9, vec![(9, EdgeKind::Next)]),
+                (9, 10, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn switch_blocks() {
+        // iload_0; tableswitch 1..2 (default ret, case 1 ret, case 2 ret), padded to offset 4; ret: iconst_0; ireturn
+        let code = [
+            0x1a, 0xaa, 0x00, 0x00, 0, 0, 0, 23, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 23, 0, 0, 0, 23,
+            0x03, 0xac,
+        ];
+        let blocks = basic_blocks(&code).unwrap();
+
+        assert_eq!(
+            summary(&blocks),
+            vec![
+                (
+                    0,
+                    24,
+                    vec![
+                        (24, EdgeKind::Default),
+                        (24, EdgeKind::Case(1)),
+                        (24, EdgeKind::Case(2)),
+                    ]
+                ),
+                (24, 26, vec![]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io::Error;
+
+/// Source line of the instructions starting at a program memory address.
+#[derive(Serialize, Deserialize)]
+pub struct LineEntry {
+    pub addr: u16,
+    pub line: u16,
+}
+
+/// Local variable array entry name and type, valid for the instructions from `start` up to `end`.
+#[derive(Serialize, Deserialize)]
+pub struct LocalEntry {
+    pub index: u16,
+    pub name: String,
+    pub descriptor: String,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Debug information of a method placed in program memory.
+#[derive(Serialize, Deserialize)]
+pub struct MethodDebugInfo {
+    pub name: String,
+    pub addr: u16,
+    pub size: u16,
+    pub lines: Vec<LineEntry>,
+    pub locals: Vec<LocalEntry>,
+}
+
+/// Debug information of a Bali binary, mapping program memory addresses to methods and source lines.
+#[derive(Serialize, Deserialize, Default)]
+pub struct DebugInfo {
+    pub source: Option<String>,
+    pub methods: Vec<MethodDebugInfo>,
+}
+
+impl DebugInfo {
+    ///
+    /// Looks up the method containing a program memory address.
+    ///
+    pub fn method(&self, addr: u16) -> Option<&MethodDebugInfo> {
+        self.methods
+            .iter()
+            .find(|m| m.addr <= addr && (addr as u32) < m.addr as u32 + m.size as u32)
+    }
+
+    ///
+    /// Looks up the source line of the instruction at a program memory address.
+    ///
+    pub fn line(&self, addr: u16) -> Option<u16> {
+        self.method(addr)?
+            .lines
+            .iter()
+            .take_while(|entry| entry.addr <= addr)
+            .last()
+            .map(|entry| entry.line)
+    }
+}
+
+///
+/// Reads a debug sidecar file written by `write_debuginfo`.
+///
+pub fn read_debuginfo(path: &str) -> Result<DebugInfo, Error> {
+    let content = fs::read_to_string(path)?;
+    serde_json::from_str(&content).map_err(|err| Error::other(err.to_string()))
+}
+
+///
+/// Writes debug information as a JSON sidecar file.
+///
+pub fn write_debuginfo(path: &str, debug: &DebugInfo) -> Result<(), Error> {
+    fs::write(path, serde_json::to_string_pretty(debug).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn method(name: &str, addr: u16, size: u16) -> MethodDebugInfo {
+        MethodDebugInfo {
+            name: name.to_string(),
+            addr,
+            size,
+            lines: vec![LineEntry { addr, line: 3 }],
+            locals: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let debug = DebugInfo {
+            source: None,
+            methods: vec![method("main", 0x0008, 0x10), method("end", 0xfff0, 0x10)],
+        };
+
+        assert_eq!(debug.method(0x0010).unwrap().name, "main");
+        assert!(debug.method(0x0018).is_none());
+        assert_eq!(debug.method(0xffff).unwrap().name, "end");
+        assert_eq!(debug.line(0xfff4), Some(3));
+    }
+}
@@ -0,0 +1,288 @@
+use crate::ir::{Instr, LutRef};
+use crate::memory::{LoweredMethod, MAIN_SIG};
+use crate::opcodes::opmap;
+
+use std::collections::HashMap;
+use std::io::Error;
+
+/// Worst-case usage of a Bali memory, along with the call chain from `main` reaching it.
+#[derive(Clone, Default)]
+pub struct Usage {
+    pub value: usize,
+    pub chain: Vec<String>,
+}
+
+/// Worst-case usage of the call stack, evaluation stack and LVA memories by a program.
+#[derive(Clone, Default)]
+pub struct MemoryUsage {
+    /// Number of nested method calls below `main`.
+    pub frames: Usage,
+    /// Evaluation stack words.
+    pub stack: Usage,
+    /// Local variable words of all active frames.
+    pub locals: Usage,
+}
+
+/// Stack usage of a lowered method.
+struct MethodStack {
+    /// Maximum evaluation stack height within the method.
+    max: usize,
+    /// Invoked methods along with the stack height below their arguments at the call site.
+    calls: Vec<(String, usize)>,
+}
+
+///
+/// Determines the stack heights of a method's lowered instructions, which may exceed the `max_stack` of the
+/// original method, e.g. where fixed-point arithmetic pushes shift distances.
+///
+/// The stack effect of invocations is taken from the argument count and return type of the invoked method.
+/// Returns an error if an instruction pops more values than the stack holds.
+///
+fn method_stack(
+    instrs: &[Instr],
+    methods: &HashMap<&str, &LoweredMethod>,
+) -> Result<MethodStack, Error> {
+    let opmap = opmap();
+    let mut heights: Vec<Option<usize>> = vec![None; instrs.len()];
+    let mut stack = MethodStack {
+        max: 0,
+        calls: Vec::new(),
+    };
+
+    let mut worklist: Vec<usize> = Vec::new();
+    if !instrs.is_empty() {
+        heights[0] = Some(0);
+        worklist.push(0);
+    }
+    while let Some(i) = worklist.pop() {
+        let (instr, height) = (&instrs[i], heights[i].unwrap());
+        let op = &opmap[&instr.opcode];
+
+        let (pops, pushes) = match (op.pops, op.pushes, &instr.lutref) {
+            (Some(pops), Some(pushes), _) => (pops as usize, pushes as usize),
+            (_, _, Some(LutRef::Method(callee))) => {
+                let argcount = methods
+                    .get(callee.as_str())
+                    .map_or(0, |method| method.code.argcount as usize);
+                // long results are lowered to their low word, the high word is fetched by a helper call
+                let pushes = if callee.ends_with(")V") { 0 } else { 1 };
+                if height >= argcount {
+                    stack.calls.push((callee.to_string(), height - argcount));
+                }
+                (argcount, pushes)
+            }
+            _ => (0, 0),
+        };
+        if height < pops {
+            return Err(Error::other(format!(
+                "evaluation stack underflow at {} (instruction {})",
+                op.mnemonic, i
+            )));
+        }
+        let after = height - pops + pushes;
+        stack.max = stack.max.max(height).max(after);
+
+        let mut successors: Vec<usize> = Vec::new();
+        if let Some(target) = instr.target {
+            successors.push(target);
+        }
+        if !op.is_terminator() && i + 1 < instrs.len() {
+            successors.push(i + 1);
+        }
+        for next in successors {
+            if heights[next].is_none() {
+                heights[next] = Some(after);
+                worklist.push(next);
+            }
+        }
+    }
+
+    Ok(stack)
+}
+
+struct Analysis<'a> {
+    methods: HashMap<&'a str, &'a LoweredMethod>,
+    stacks: HashMap<&'a str, MethodStack>,
+    usage: HashMap<String, MemoryUsage>,
+    /// Methods on the call chain currently visited.
+    active: Vec<String>,
+    cycles: Vec<Vec<String>>,
+}
+
+impl Analysis<'_> {
+    ///
+    /// Determines the worst-case memory usage of a method and the methods it invokes,
+    /// skipping calls that close a recursion cycle.
+    ///
+    /// Returns the usage along with whether a call was skipped while visiting the method. Such usage depends on
+    /// the call chain leading to the method, i.e. where the cycle was entered, so it is not cached.
+    ///
+    fn visit(&mut self, name: &str) -> (MemoryUsage, bool) {
+        if let Some(usage) = self.usage.get(name) {
+            return (usage.clone(), false);
+        }
+
+        let Some(method) = self.methods.get(name).copied() else {
+            return (MemoryUsage::default(), false);
+        };
+        let own = |value: usize| Usage {
+            value,
+            chain: vec![name.to_string()],
+        };
+        let mut usage = MemoryUsage {
+            frames: own(0),
+            stack: own(self.stacks.get(name).map_or(0, |stack| stack.max)),
+            locals: own(method.code.max_locals as usize),
+        };
+
+        let mut skipped = false;
+        self.active.push(name.to_string());
+        let calls = self
+            .stacks
+            .get(name)
+            .map(|stack| stack.calls.clone())
+            .unwrap_or_default();
+        for (callee, below) in calls {
+            if let Some(start) = self.active.iter().position(|active| *active == callee) {
+                let mut cycle = self.active[start..].to_vec();
+                cycle.push(callee);
+                if !self.cycles.contains(&cycle) {
+                    self.cycles.push(cycle);
+                }
+                skipped = true;
+                continue;
+            }
+
+            let (callee, callee_skipped) = self.visit(&callee);
+            skipped |= callee_skipped;
+            let chain = |usage: &Usage| [vec![name.to_string()], usage.chain.clone()].concat();
+            if 1 + callee.frames.value > usage.frames.value {
+                usage.frames = Usage {
+                    value: 1 + callee.frames.value,
+                    chain: chain(&callee.frames),
+                };
+            }
+            if below + callee.stack.value > usage.stack.value {
+                usage.stack = Usage {
+                    value: below + callee.stack.value,
+                    chain: chain(&callee.stack),
+                };
+            }
+            let locals = method.code.max_locals as usize + callee.locals.value;
+            if locals > usage.locals.value {
+                usage.locals = Usage {
+                    value: locals,
+                    chain: chain(&callee.locals),
+                };
+            }
+        }
+        self.active.pop();
+
+        if !skipped {
+            self.usage.insert(name.to_string(), usage.clone());
+        }
+        (usage, skipped)
+    }
+}
+
+///
+/// Determines the worst-case usage of the call stack, evaluation stack and LVA memories by the lowered methods
+/// of a program, starting at `main`.
+///
+/// Returns the usage along with the recursion cycles found, as call chains starting and ending with the same method.
+/// The usage of recursive programs only covers a single pass through each cycle.
+/// Returns an error if the stack height of a method cannot be determined.
+///
+pub fn memory_usage(methods: &[LoweredMethod]) -> Result<(MemoryUsage, Vec<Vec<String>>), Error> {
+    let methods: HashMap<&str, &LoweredMethod> = methods
+        .iter()
+        .map(|method| (method.name.as_str(), method))
+        .collect();
+
+    let mut stacks: HashMap<&str, MethodStack> = HashMap::new();
+    for (name, method) in &methods {
+        if let Ok(instrs) = &method.instrs {
+            let stack = method_stack(instrs, &methods)
+                .map_err(|err| Error::other(format!("method {}: {}", name, err)))?;
+            stacks.insert(name, stack);
+        }
+    }
+
+    let mut analysis = Analysis {
+        methods,
+        stacks,
+        usage: HashMap::new(),
+        active: Vec::new(),
+        cycles: Vec::new(),
+    };
+    let (usage, _) = analysis.visit(MAIN_SIG);
+
+    Ok((usage, analysis.cycles))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimize::Savings;
+    use crate::structs::BaliCode;
+
+    const POP: u8 = 0x57;
+    const RETURN: u8 = 0xb1;
+
+    fn method(name: &str, instrs: Vec<Instr>) -> LoweredMethod {
+        LoweredMethod {
+            name: name.to_string(),
+            class: 0,
+            code: BaliCode {
+                access_flags: 0,
+                max_stack: 0,
+                max_locals: 1,
+                argcount: 0,
+                code: Vec::new(),
+                lines: Vec::new(),
+                locals: Vec::new(),
+            },
+            instrs: Ok(instrs),
+            callees: Vec::new(),
+            savings: Savings::default(),
+        }
+    }
+
+    ///
+    /// Returns instructions pushing `count` values, invoking the given method and popping the values again.
+    ///
+    fn call_above(count: usize, callee: &str) -> Vec<Instr> {
+        let mut instrs: Vec<Instr> = (0..count).map(|_| Instr::push(1)).collect();
+        instrs.push(Instr::call(callee));
+        instrs.extend((0..count).map(|_| Instr::new(POP, Vec::new())));
+        instrs
+    }
+
+    #[test]
+    fn recursion() {
+        // main calls a, then b above 5 stack values, while a and b call each other
+        let mut main = call_above(0, "a()V");
+        main.extend(call_above(5, "b()V"));
+        main.push(Instr::new(RETURN, Vec::new()));
+        let pop = || Instr::new(POP, Vec::new());
+        let mut a = vec![Instr::push(1), Instr::push(1), pop(), pop()];
+        a.extend(call_above(0, "b()V"));
+        a.push(Instr::new(RETURN, Vec::new()));
+        let mut b = call_above(3, "a()V");
+        b.push(Instr::new(RETURN, Vec::new()));
+
+        let methods = [method(MAIN_SIG, main), method("a()V", a), method("b()V", b)];
+        let (usage, cycles) = memory_usage(&methods).unwrap();
+
+        // main -> b -> a reaches 5 + 3 + 2 words, although a and b were first visited from main -> a,
+        // where the call of a by b is skipped
+        assert_eq!(usage.stack.value, 10);
+        assert_eq!(usage.stack.chain, vec![MAIN_SIG, "b()V", "a()V"]);
+        assert_eq!(usage.frames.value, 2);
+        assert_eq!(usage.locals.value, 3);
+        assert_eq!(
+            cycles,
+            vec![vec!["a()V", "b()V", "a()V"], vec!["b()V", "a()V", "b()V"]]
+        );
+    }
+}
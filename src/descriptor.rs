@@ -0,0 +1,224 @@
+use std::fmt;
+use std::io::Error;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+/// Type of a field, local variable, method parameter or return value, given by a JVM field descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldType {
+    Byte,
+    Char,
+    Double,
+    Float,
+    Int,
+    Long,
+    Short,
+    Boolean,
+    /// Object type, given by its fully qualified binary class name, e.g. `java/lang/String`.
+    Object(String),
+    Array(Box<FieldType>),
+}
+
+/// Parameter and return types of a method, given by a JVM method descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodDescriptor {
+    pub params: Vec<FieldType>,
+    /// Return type of the method, `None` for `void` methods.
+    pub ret: Option<FieldType>,
+}
+
+impl FieldType {
+    ///
+    /// Returns the number of local variable array or stack slots a value of this type occupies.
+    ///
+    pub fn slots(&self) -> u16 {
+        match self {
+            FieldType::Long | FieldType::Double => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl MethodDescriptor {
+    ///
+    /// Returns the number of slots the method arguments occupy, excluding `this` for instance methods.
+    ///
+    pub fn arg_slots(&self) -> u16 {
+        self.params.iter().map(|param| param.slots()).sum()
+    }
+}
+
+fn invalid(descriptor: &str) -> Error {
+    Error::other(format!("invalid descriptor '{}'", descriptor))
+}
+
+fn parse_field_type(chars: &mut Peekable<Chars>, descriptor: &str) -> Result<FieldType, Error> {
+    let fieldtype = match chars.next().ok_or_else(|| invalid(descriptor))? {
+        'B' => FieldType::Byte,
+        'C' => FieldType::Char,
+        'D' => FieldType::Double,
+        'F' => FieldType::Float,
+        'I' => FieldType::Int,
+        'J' => FieldType::Long,
+        'S' => FieldType::Short,
+        'Z' => FieldType::Boolean,
+        'L' => {
+            let mut name = String::new();
+            loop {
+                match chars.next() {
+                    Some(';') => break,
+                    Some(c) => name.push(c),
+                    // class names are terminated by a semicolon
+                    None => return Err(invalid(descriptor)),
+                }
+            }
+            if name.is_empty() {
+                return Err(invalid(descriptor));
+            }
+            FieldType::Object(name)
+        }
+        '[' => FieldType::Array(Box::new(parse_field_type(chars, descriptor)?)),
+        _ => return Err(invalid(descriptor)),
+    };
+
+    Ok(fieldtype)
+}
+
+impl FromStr for FieldType {
+    type Err = Error;
+
+    fn from_str(descriptor: &str) -> Result<Self, Self::Err> {
+        let mut chars = descriptor.chars().peekable();
+        let fieldtype = parse_field_type(&mut chars, descriptor)?;
+
+        match chars.next() {
+            None => Ok(fieldtype),
+            Some(_) => Err(invalid(descriptor)),
+        }
+    }
+}
+
+impl FromStr for MethodDescriptor {
+    type Err = Error;
+
+    fn from_str(descriptor: &str) -> Result<Self, Self::Err> {
+        let mut chars = descriptor.chars().peekable();
+        if chars.next() != Some('(') {
+            return Err(invalid(descriptor));
+        }
+
+        let mut params: Vec<FieldType> = Vec::new();
+        while chars.peek().ok_or_else(|| invalid(descriptor))? != &')' {
+            params.push(parse_field_type(&mut chars, descriptor)?);
+        }
+        chars.next();
+
+        let ret = if chars.peek() == Some(&'V') {
+            chars.next();
+            None
+        } else {
+            Some(parse_field_type(&mut chars, descriptor)?)
+        };
+
+        match chars.next() {
+            None => Ok(MethodDescriptor { params, ret }),
+            Some(_) => Err(invalid(descriptor)),
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    /// Formats the type as in Java source code, with object types given by their simple class name.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldType::Byte => write!(f, "byte"),
+            FieldType::Char => write!(f, "char"),
+            FieldType::Double => write!(f, "double"),
+            FieldType::Float => write!(f, "float"),
+            FieldType::Int => write!(f, "int"),
+            FieldType::Long => write!(f, "long"),
+            FieldType::Short => write!(f, "short"),
+            FieldType::Boolean => write!(f, "boolean"),
+            FieldType::Object(name) => write!(f, "{}", name.rsplit('/').next().unwrap()),
+            FieldType::Array(element) => write!(f, "{}[]", element),
+        }
+    }
+}
+
+///
+/// Splits a method signature (method name followed by its descriptor) into name and descriptor.
+///
+/// Returns an error if the signature contains no valid method descriptor.
+///
+pub fn split_signature(sig: &str) -> Result<(&str, MethodDescriptor), Error> {
+    let start = sig.find('(').ok_or_else(|| invalid(sig))?;
+    Ok((&sig[..start], sig[start..].parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_types() {
+        assert_eq!("I".parse::<FieldType>().unwrap(), FieldType::Int);
+        assert_eq!(
+            "Ljava/lang/String;".parse::<FieldType>().unwrap(),
+            FieldType::Object("java/lang/String".to_string())
+        );
+        assert_eq!(
+            "[[J".parse::<FieldType>().unwrap(),
+            FieldType::Array(Box::new(FieldType::Array(Box::new(FieldType::Long))))
+        );
+        assert_eq!(
+            "[Ljava/lang/String;"
+                .parse::<FieldType>()
+                .unwrap()
+                .to_string(),
+            "String[]"
+        );
+    }
+
+    #[test]
+    fn invalid_field_types() {
+        for descriptor in ["", "V", "X", "L;", "Ljava/lang/String", "[", "II"] {
+            assert!(descriptor.parse::<FieldType>().is_err(), "{}", descriptor);
+        }
+    }
+
+    #[test]
+    fn method_descriptors() {
+        let descriptor: MethodDescriptor = "(IJ[ILjava/lang/Object;D)V".parse().unwrap();
+
+        assert_eq!(descriptor.params.len(), 5);
+        assert_eq!(descriptor.ret, None);
+        assert_eq!(descriptor.arg_slots(), 1 + 2 + 1 + 1 + 2);
+
+        let descriptor: MethodDescriptor = "()Ljava/lang/String;".parse().unwrap();
+        assert!(descriptor.params.is_empty());
+        assert_eq!(
+            descriptor.ret,
+            Some(FieldType::Object("java/lang/String".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_method_descriptors() {
+        for descriptor in ["", "I", "(I", "(I)", "(V)V", "()VI", "(Ljava/lang/String)V"] {
+            assert!(
+                descriptor.parse::<MethodDescriptor>().is_err(),
+                "{}",
+                descriptor
+            );
+        }
+    }
+
+    #[test]
+    fn signatures() {
+        let (name, descriptor) = split_signature("fib(I)I").unwrap();
+
+        assert_eq!(name, "fib");
+        assert_eq!(descriptor.ret, Some(FieldType::Int));
+        assert!(split_signature("fib").is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use crate::ir::Instr;
+use crate::runtime::helper;
+use crate::structs::ConstPoolValue;
+
+use std::collections::btree_map::BTreeMap;
+use std::fmt;
+use std::io::Error;
+use std::str::FromStr;
+
+const ICONST_0: u8 = 0x03;
+const ILOAD: u8 = 0x15;
+const ILOAD_0: u8 = 0x1a;
+const ISTORE: u8 = 0x36;
+const ISTORE_0: u8 = 0x3b;
+const POP: u8 = 0x57;
+const DUP: u8 = 0x59;
+const SWAP: u8 = 0x5f;
+const IADD: u8 = 0x60;
+const ISUB: u8 = 0x64;
+const IMUL: u8 = 0x68;
+const IREM: u8 = 0x70;
+const INEG: u8 = 0x74;
+const ISHL: u8 = 0x78;
+const ISHR: u8 = 0x7a;
+const IUSHR: u8 = 0x7c;
+const IRETURN: u8 = 0xac;
+
+/// Representation of `float` values on Bali.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FloatMode {
+    /// IEEE-754 single-precision bit patterns, with arithmetic emulated by runtime helpers.
+    #[default]
+    Soft,
+    /// Q16.16 fixed-point ints, with arithmetic lowered to int arithmetic.
+    Fixed16,
+}
+
+impl FromStr for FloatMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "soft" => Ok(FloatMode::Soft),
+            "fixed16" => Ok(FloatMode::Fixed16),
+            _ => Err(format!(
+                "invalid float mode '{}', expected soft or fixed16",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FloatMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FloatMode::Soft => write!(f, "soft"),
+            FloatMode::Fixed16 => write!(f, "fixed16"),
+        }
+    }
+}
+
+/// Number of fractional bits of fixed-point `float` values.
+const FRACTION_BITS: i32 = 16;
+
+fn load(index: u8) -> Instr {
+    match index {
+        0..=3 => Instr::new(ILOAD_0 + index, Vec::new()),
+        _ => Instr::new(ILOAD, vec![index]),
+    }
+}
+
+fn store(index: u8) -> Instr {
+    match index {
+        0..=3 => Instr::new(ISTORE_0 + index, Vec::new()),
+        _ => Instr::new(ISTORE, vec![index]),
+    }
+}
+
+fn op(opcode: u8) -> Instr {
+    Instr::new(opcode, Vec::new())
+}
+
+///
+/// Returns the instructions fetching the high word of a long result after calling a method returning a long,
+/// leaving the high word below the returned low word on the stack.
+///
+pub fn long_result() -> Vec<Instr> {
+    vec![Instr::call(&helper("high()I")), op(SWAP)]
+}
+
+fn long_call(sig: &str) -> Vec<Instr> {
+    let mut instrs = vec![Instr::call(&helper(sig))];
+    instrs.extend(long_result());
+    instrs
+}
+
+///
+/// Lowers an instruction operating on `long` values to instructions operating on pairs of ints,
+/// with the high word in the lower local variable or stack slot.
+///
+/// Arithmetic is emulated by runtime helper calls.
+/// Returns `None` if the instruction does not operate on `long` values.
+///
+pub fn lower_long(
+    mnemonic: &str,
+    instr: &Instr,
+    constpool: &BTreeMap<u16, ConstPoolValue>,
+) -> Option<Result<Vec<Instr>, Error>> {
+    let instrs = match mnemonic {
+        "lconst_0" => vec![op(ICONST_0), op(ICONST_0)],
+        "lconst_1" => vec![op(ICONST_0), Instr::push(1)],
+        "ldc2_w" => {
+            let index = u16::from_be_bytes([instr.operands[0], instr.operands[1]]);
+            match constpool.get(&index) {
+                Some(ConstPoolValue::Long(value)) => vec![
+                    Instr::push((value >> 32) as i32),
+                    Instr::push(*value as i32),
+                ],
+                _ => {
+                    return Some(Err(Error::other(format!(
+                        "constant #{} is not a long constant",
+                        index
+                    ))))
+                }
+            }
+        }
+        "lload" | "lstore" => {
+            let index = instr.operands[0];
+            let Some(low) = index.checked_add(1) else {
+                return Some(Err(Error::other(format!(
+                    "{} of local variable {} exceeds the local variables addressable without wide",
+                    mnemonic, index
+                ))));
+            };
+            match mnemonic {
+                "lload" => vec![load(index), load(low)],
+                _ => vec![store(low), store(index)],
+            }
+        }
+        "lload_0" | "lload_1" | "lload_2" | "lload_3" => {
+            let index = mnemonic.as_bytes()[6] - b'0';
+            vec![load(index), load(index + 1)]
+        }
+        "lstore_0" | "lstore_1" | "lstore_2" | "lstore_3" => {
+            let index = mnemonic.as_bytes()[7] - b'0';
+            vec![store(index + 1), store(index)]
+        }
+        "pop2" => vec![op(POP), op(POP)],
+        "i2l" => vec![op(DUP), Instr::push(31), op(ISHR), op(SWAP)],
+        "l2i" => vec![op(SWAP), op(POP)],
+        "ladd" | "lsub" | "lmul" | "land" | "lor" | "lxor" => {
+            long_call(&format!("{}(IIII)I", mnemonic))
+        }
+        "lneg" => long_call("lneg(II)I"),
+        "lshl" | "lshr" | "lushr" => long_call(&format!("{}(III)I", mnemonic)),
+        "lcmp" => vec![Instr::call(&helper("lcmp(IIII)I"))],
+        "lreturn" => vec![Instr::call(&helper("lreturn(II)I")), op(IRETURN)],
+        _ => return None,
+    };
+
+    Some(Ok(instrs))
+}
+
+/// Int instructions which runtime helpers of the same name replace on targets lacking them.
+const INTRINSICS: [&str; 4] = ["idiv", "irem", "imul", "ishr"];
+
+///
+/// Returns the call of the runtime helper replacing an int instruction on targets lacking it.
+///
+/// Returns `None` if there is no helper for the instruction.
+///
+pub fn intrinsic(mnemonic: &str) -> Option<Instr> {
+    INTRINSICS
+        .contains(&mnemonic)
+        .then(|| Instr::call(&helper(&format!("{}(II)I", mnemonic))))
+}
+
+///
+/// Converts a `float` constant to its representation in the given mode.
+///
+/// Returns an error if the constant exceeds the range of Q16.16 fixed-point values.
+///
+fn float_constant(value: f32, mode: FloatMode) -> Result<i32, Error> {
+    match mode {
+        FloatMode::Soft => Ok(value.to_bits() as i32),
+        FloatMode::Fixed16 => {
+            let fixed = (value as f64 * (1 << FRACTION_BITS) as f64).round();
+            if fixed < i32::MIN as f64 || fixed > i32::MAX as f64 || fixed.is_nan() {
+                return Err(Error::other(format!(
+                    "constant {} exceeds the range of Q16.16 fixed-point values",
+                    value
+                )));
+            }
+            Ok(fixed as i32)
+        }
+    }
+}
+
+///
+/// Lowers an instruction operating on `float` values to instructions operating on ints.
+///
+/// In soft-float mode, floats are represented by their bit patterns and arithmetic and conversions
+/// are emulated by runtime helper calls. In fixed-point mode, floats are Q16.16 fixed-point values
+/// and arithmetic is lowered to int arithmetic, with multiplication shifting both operands by half the fraction
+/// bits, losing the lower 8 bits of precision. Division is done at full precision by a runtime helper.
+/// Returns `None` if the instruction does not operate on `float` values.
+///
+pub fn lower_float(
+    mnemonic: &str,
+    instr: &Instr,
+    constpool: &BTreeMap<u16, ConstPoolValue>,
+    mode: FloatMode,
+) -> Option<Result<Vec<Instr>, Error>> {
+    let constant = match mnemonic {
+        "fconst_0" | "fconst_1" | "fconst_2" => Some((mnemonic.as_bytes()[7] - b'0') as f32),
+        "ldc" => match constpool.get(&(instr.operands[0] as u16)) {
+            Some(ConstPoolValue::Float(value)) => Some(*value),
+            _ => return None,
+        },
+        _ => None,
+    };
+    if let Some(value) = constant {
+        return Some(float_constant(value, mode).map(|value| vec![Instr::push(value)]));
+    }
+
+    let half = FRACTION_BITS / 2;
+    let instrs = match (mnemonic, mode) {
+        // loads, stores and returns of floats only move their int representation, so the int variants are used,
+        // whose opcodes precede the float variants by 8 for the short forms and 2 for the others
+        (
+            "fload_0" | "fload_1" | "fload_2" | "fload_3" | "fstore_0" | "fstore_1" | "fstore_2"
+            | "fstore_3",
+            _,
+        ) => vec![op(instr.opcode - 8)],
+        ("fload" | "fstore" | "faload" | "fastore" | "freturn", _) => {
+            vec![Instr::new(instr.opcode - 2, instr.operands.clone())]
+        }
+        ("fadd" | "fsub" | "fmul" | "fdiv" | "fcmpl" | "fcmpg", FloatMode::Soft) => {
+            vec![Instr::call(&helper(&format!("{}(II)I", mnemonic)))]
+        }
+        ("fneg" | "i2f" | "f2i", FloatMode::Soft) => {
+            vec![Instr::call(&helper(&format!("{}(I)I", mnemonic)))]
+        }
+        ("fadd", FloatMode::Fixed16) => vec![op(IADD)],
+        ("fsub", FloatMode::Fixed16) => vec![op(ISUB)],
+        ("fneg", FloatMode::Fixed16) => vec![op(INEG)],
+        ("frem", FloatMode::Fixed16) => vec![op(IREM)],
+        ("fmul", FloatMode::Fixed16) => vec![
+            Instr::push(half),
+            op(ISHR),
+            op(SWAP),
+            Instr::push(half),
+            op(ISHR),
+            op(IMUL),
+        ],
+        ("fdiv", FloatMode::Fixed16) => vec![Instr::call(&helper("fixdiv(II)I"))],
+        ("fcmpl" | "fcmpg", FloatMode::Fixed16) => vec![Instr::call(&helper("icmp(II)I"))],
+        ("i2f", FloatMode::Fixed16) => vec![Instr::push(FRACTION_BITS), op(ISHL)],
+        // the arithmetic shift rounds towards negative infinity, so the fraction mask is added to negative values
+        // first to round towards zero as the JVM does
+        ("f2i", FloatMode::Fixed16) => vec![
+            op(DUP),
+            Instr::push(31),
+            op(ISHR),
+            Instr::push(32 - FRACTION_BITS),
+            op(IUSHR),
+            op(IADD),
+            Instr::push(FRACTION_BITS),
+            op(ISHR),
+        ],
+        _ => return None,
+    };
+
+    Some(Ok(instrs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{binarygen, Options};
+    use crate::sim::simulate;
+    use crate::structs::ClassFile;
+
+    use binrw::BinReaderExt;
+    use std::io::Cursor;
+
+    #[test]
+    fn long_locals() {
+        let constpool = BTreeMap::new();
+        let lower = |opcode: u8, index: u8| {
+            let instr = Instr::new(opcode, vec![index]);
+            let mnemonic = if opcode == 0x16 { "lload" } else { "lstore" };
+            lower_long(mnemonic, &instr, &constpool).unwrap()
+        };
+
+        let instrs = lower(0x16, 4).unwrap();
+        assert_eq!(instrs[0].operands, vec![4]);
+        assert_eq!(instrs[1].operands, vec![5]);
+        let instrs = lower(0x37, 254).unwrap();
+        assert_eq!(instrs[0].operands, vec![255]);
+        assert_eq!(instrs[1].operands, vec![254]);
+        assert!(lower(0x16, 255).is_err());
+        assert!(lower(0x37, 255).is_err());
+    }
+
+    #[test]
+    fn fixed_point() {
+        let classfile = include_bytes!("resources/tests/FixedPoint.class");
+        let class: ClassFile = Cursor::new(&classfile[..]).read_be().unwrap();
+        let options = Options {
+            float: FloatMode::Fixed16,
+            ..Default::default()
+        };
+        let bin = binarygen(&[class], &options).unwrap();
+        let result = simulate(&bin, &options.target.lut).unwrap();
+
+        // 200 / 4, 1 / 0.001 (0.001 rounds to 66 / 65536), -7 / 4 * 2, 200 / -3, (int) -3.5, -3.5 * 2, 1 / 0
+        let memory = &result.memory[result.memory.len() - 7..];
+        assert_eq!(memory, [50, 992, -3, -66, -3, -7, 32767]);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::opcodes::opmap;
+
+use std::io::Error;
+
+const ICONST_0: u8 = 0x03;
+const BIPUSH: u8 = 0x10;
+const SIPUSH: u8 = 0x11;
+const LDC: u8 = 0x12;
+const INVOKESTATIC: u8 = 0xb8;
+
+/// Entry of the method and constant LUT an instruction operand refers to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LutRef {
+    /// Method given by its signature, prefixed with the class name for methods outside the main class.
+    Method(String),
+    /// Integer constant given by its value.
+    Constant(i32),
+}
+
+/// Single bytecode instruction, used for rewriting method bytecode before emission.
+pub struct Instr {
+    pub opcode: u8,
+    pub operands: Vec<u8>,
+    /// Index of the branch target instruction, if the instruction is a branch.
+    pub target: Option<usize>,
+    /// Offset of the instruction in the original method bytecode, `None` for inserted instructions.
+    pub pc: Option<u16>,
+    /// Source line the instruction was compiled from, if known.
+    pub line: Option<u16>,
+    /// LUT entry the operand refers to, resolved to a LUT index once the LUT layout is known.
+    pub lutref: Option<LutRef>,
+}
+
+impl Instr {
+    pub fn new(opcode: u8, operands: Vec<u8>) -> Self {
+        Instr {
+            opcode,
+            operands,
+            target: None,
+            pc: None,
+            line: None,
+            lutref: None,
+        }
+    }
+
+    pub fn call(method: &str) -> Self {
+        let mut instr = Instr::new(INVOKESTATIC, vec![0, 0]);
+        instr.lutref = Some(LutRef::Method(method.to_string()));
+        instr
+    }
+
+    ///
+    /// Returns the shortest instruction pushing the given int, loading it from the constant LUT
+    /// if it exceeds the range of `sipush`.
+    ///
+    pub fn push(value: i32) -> Self {
+        match value {
+            -1..=5 => Instr::new((ICONST_0 as i32 + value) as u8, Vec::new()),
+            _ => match (i8::try_from(value), i16::try_from(value)) {
+                (Ok(value), _) => Instr::new(BIPUSH, vec![value as u8]),
+                (_, Ok(value)) => Instr::new(SIPUSH, value.to_be_bytes().to_vec()),
+                _ => {
+                    let mut instr = Instr::new(LDC, vec![0]);
+                    instr.lutref = Some(LutRef::Constant(value));
+                    instr
+                }
+            },
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        1 + self.operands.len()
+    }
+}
+
+///
+/// Decodes method bytecode into a list of instructions.
+///
+/// Branch offsets are resolved to instruction indices, so instructions can be inserted or removed
+/// before encoding. Each instruction is annotated with the source line of the last line table entry
+/// (`start_pc`, `line_number`) that starts at or before the instruction.
+/// Returns an error if an opcode is unknown, an instruction is truncated
+/// or a branch does not target the start of an instruction.
+///
+pub fn decode(code: &[u8], lines: &[(u16, u16)]) -> Result<Vec<Instr>, Error> {
+    let opmap = opmap();
+    let mut instrs: Vec<Instr> = Vec::new();
+    let mut addrs: Vec<usize> = Vec::new();
+
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = opmap.get(&code[pc]).ok_or_else(|| {
+            Error::other(format!("unknown opcode {:#04x} at pc {}", code[pc], pc))
+        })?;
+        let len = op.operand_len(code, pc).ok_or_else(|| {
+            Error::other(format!(
+                "truncated {} instruction at pc {}",
+                op.mnemonic, pc
+            ))
+        })?;
+        let mut instr = Instr::new(code[pc], code[pc + 1..pc + 1 + len].to_vec());
+        instr.pc = Some(pc as u16);
+        instr.line = lines
+            .iter()
+            .filter(|(start_pc, _)| *start_pc as usize <= pc)
+            .max_by_key(|(start_pc, _)| *start_pc)
+            .map(|(_, line)| *line);
+
+        addrs.push(pc);
+        instrs.push(instr);
+        pc += 1 + len;
+    }
+
+    for (i, instr) in instrs.iter_mut().enumerate() {
+        if opmap[&instr.opcode].is_branch() {
+            let offset = match instr.operands[..] {
+                [a, b] => i16::from_be_bytes([a, b]) as isize,
+                [a, b, c, d] => i32::from_be_bytes([a, b, c, d]) as isize,
+                _ => continue,
+            };
+            let target = addrs[i] as isize + offset;
+            instr.target = addrs.iter().position(|addr| *addr as isize == target);
+            if instr.target.is_none() {
+                return Err(Error::other(format!(
+                    "branch at pc {} targets pc {}, which is not an instruction boundary",
+                    addrs[i], target
+                )));
+            }
+        }
+    }
+
+    Ok(instrs)
+}
+
+///
+/// Replaces each instruction with the sequence of instructions returned by `f`.
+///
+/// Branch targets, including those of instructions returned by `f`, refer to instruction indices of the
+/// original list and are redirected to the start of the sequence replacing the target instruction.
+/// Inserted instructions inherit the source line of the replaced instruction,
+/// and the first instruction of a sequence inherits its bytecode offset.
+///
+pub fn expand<F>(instrs: Vec<Instr>, mut f: F) -> Result<Vec<Instr>, Error>
+where
+    F: FnMut(Instr) -> Result<Vec<Instr>, Error>,
+{
+    let mut starts: Vec<usize> = Vec::with_capacity(instrs.len());
+    let mut expanded: Vec<Instr> = Vec::with_capacity(instrs.len());
+
+    for instr in instrs {
+        let (pc, line) = (instr.pc, instr.line);
+        starts.push(expanded.len());
+
+        for (i, mut new) in f(instr)?.into_iter().enumerate() {
+            if i == 0 && new.pc.is_none() {
+                new.pc = pc;
+            }
+            new.line = new.line.or(line);
+            expanded.push(new);
+        }
+    }
+
+    for instr in expanded.iter_mut() {
+        if let Some(target) = instr.target {
+            instr.target = Some(starts[target]);
+        }
+    }
+
+    Ok(expanded)
+}
+
+///
+/// Encodes a list of instructions into bytecode, recomputing branch offsets from the branch targets.
+///
+/// Returns the bytecode along with the address of each instruction within the bytecode,
+/// or an error if an instruction lies beyond the 16-bit address range or a branch offset exceeds its operand.
+///
+pub fn encode(instrs: &[Instr]) -> Result<(Vec<u8>, Vec<u16>), Error> {
+    let mut addrs: Vec<u16> = Vec::with_capacity(instrs.len());
+    let mut addr: usize = 0;
+    for instr in instrs {
+        addrs.push(u16::try_from(addr).map_err(|_| {
+            Error::other(format!(
+                "instruction at address {} exceeds the 16 bit address range",
+                addr
+            ))
+        })?);
+        addr += instr.size();
+    }
+
+    let mut code: Vec<u8> = Vec::with_capacity(addr);
+    for (i, instr) in instrs.iter().enumerate() {
+        code.push(instr.opcode);
+        match instr.target {
+            Some(target) => {
+                let offset = addrs[target] as i32 - addrs[i] as i32;
+                let width = instr.operands.len();
+                let bits = 8 * width as u32;
+                if bits < 32 && (offset < -(1 << (bits - 1)) || offset >= 1 << (bits - 1)) {
+                    return Err(Error::other(format!(
+                        "branch offset {} at address {} exceeds the {} bit operand",
+                        offset, addrs[i], bits
+                    )));
+                }
+                code.extend_from_slice(&offset.to_be_bytes()[4 - width..]);
+            }
+            None => code.extend_from_slice(&instr.operands),
+        }
+    }
+
+    Ok((code, addrs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branch_round_trip() {
+        // iconst_0; ifeq +5; iconst_1; pop; goto -6
+        let code = [0x03, 0x99, 0x00, 0x05, 0x04, 0x57, 0xa7, 0xff, 0xfa];
+        let instrs = decode(&code, &[]).unwrap();
+
+        assert_eq!(instrs[1].target, Some(4));
+        assert_eq!(instrs[4].target, Some(0));
+        assert_eq!(encode(&instrs).unwrap().0, code);
+    }
+
+    #[test]
+    fn branch_into_instruction() {
+        // goto +1, landing on the second byte of the goto itself
+        assert!(decode(&[0xa7, 0x00, 0x01], &[]).is_err());
+        // goto -1, before the start of the method
+        assert!(decode(&[0xa7, 0xff, 0xff], &[]).is_err());
+    }
+
+    #[test]
+    fn encode_ranges() {
+        // goto over 40000 nops, beyond the range of its 16-bit offset
+        let mut instrs: Vec<Instr> = (0..40001).map(|_| Instr::new(0x00, Vec::new())).collect();
+        instrs[0] = Instr::new(0xa7, vec![0, 0]);
+        instrs[0].target = Some(40000);
+        assert!(encode(&instrs).is_err());
+        instrs[0].target = Some(10000);
+        assert!(encode(&instrs).is_ok());
+
+        // instructions beyond the 16-bit address range
+        let instrs: Vec<Instr> = (0..70000).map(|_| Instr::new(0x00, Vec::new())).collect();
+        assert!(encode(&instrs).is_err());
+    }
+}
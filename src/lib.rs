@@ -1,4 +1,17 @@
+pub mod attributes;
+pub mod cfg;
+pub mod debug;
+pub mod depth;
+pub mod descriptor;
+pub mod emulate;
+pub mod ir;
 pub mod memory;
 pub mod opcodes;
+pub mod optimize;
+pub mod runner;
+pub mod runtime;
+pub mod sim;
 pub mod structs;
+pub mod target;
 pub mod uart;
+pub mod verify;
@@ -1,22 +1,37 @@
 use clap::{Parser, Subcommand};
 
-mod structs;
-use structs::*;
+use bake::cfg::*;
 
-mod memory;
-use memory::*;
+use bake::debug::*;
 
-mod opcodes;
-use opcodes::*;
+use bake::descriptor::*;
+
+use bake::emulate::FloatMode;
+
+use bake::ir::encode;
+
+use bake::structs::*;
+
+use bake::memory::*;
+
+use bake::opcodes::*;
 
 mod print;
 use print::*;
 
-mod uart;
-use uart::*;
+use bake::runner::*;
+
+use bake::sim::*;
+
+use bake::target::*;
+
+use bake::uart;
+use bake::uart::*;
+
+use bake::verify::*;
 
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Error, Read, Write};
 use std::path::Path;
 
 #[derive(Parser, Debug)]
@@ -48,32 +63,262 @@ enum Commands {
         /// Set this flag to print the hex output of the binary
         #[clap(short, long)]
         output: bool,
+        /// Representation of float values, soft (IEEE-754 emulation) or fixed16 (Q16.16 fixed-point)
+        #[clap(long, default_value = "soft")]
+        float: FloatMode,
+        /// Target profile, a built-in name (bali-v1, bali-v1-long, bali-v1-nodiv) or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
+        /// Set this flag to keep all methods of the main class, including those unreachable from main
+        #[clap(long)]
+        keep_all: bool,
+        /// Set this flag to apply peephole optimisations to the generated code
+        #[clap(short = 'O', long)]
+        optimize: bool,
+    },
+    /// Export call graph of JVM class file, with methods left out of the binary drawn dashed
+    Callgraph {
+        /// Path of the class file to analyze
+        #[clap(short, long)]
+        classfile: String,
+        /// Output format, dot (Graphviz) or mermaid
+        #[clap(long, default_value = "dot")]
+        format: GraphFormat,
+        /// Representation of float values, deciding on the runtime helpers called, soft or fixed16
+        #[clap(long, default_value = "soft")]
+        float: FloatMode,
+        /// Target profile, deciding on the runtime helpers called, a built-in name or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
+    },
+    /// Export control flow graph of a method from JVM class file
+    Cfg {
+        /// Path of the class file containing the method
+        #[clap(short, long)]
+        classfile: String,
+        /// Name or signature of the method, e.g. main or fib(I)I
+        #[clap(short, long)]
+        method: String,
+        /// Output format, dot (Graphviz) or mermaid
+        #[clap(long, default_value = "dot")]
+        format: GraphFormat,
+        /// Set this flag to show the method after lowering, as it is emitted into the Bali binary
+        #[clap(short, long)]
+        lowered: bool,
+        /// Set this flag to show the lowered method after peephole optimisation
+        #[clap(short = 'O', long, requires = "lowered")]
+        optimize: bool,
+        /// Representation of float values in the lowered method, soft or fixed16
+        #[clap(long, default_value = "soft")]
+        float: FloatMode,
+        /// Target profile the method is lowered for, a built-in name or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
+    },
+    /// Disassemble Bali binary
+    Disasm {
+        /// Path of the binary file to disassemble
+        #[clap(short, long)]
+        bin: String,
+        /// Path of the debug sidecar file of the binary, defaults to the `.dbg` file next to the binary
+        #[clap(short, long)]
+        debug: Option<String>,
+        /// Target profile, a built-in name (bali-v1, bali-v1-long, bali-v1-nodiv) or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
     },
     /// Generate Bali file for use with SystemVerilog testbenches from JVM class file
     Testfile {
         #[clap(short, long)]
         classfile: String,
+        /// Representation of float values, soft (IEEE-754 emulation) or fixed16 (Q16.16 fixed-point)
+        #[clap(long, default_value = "soft")]
+        float: FloatMode,
+        /// Set this flag to keep all methods of the main class, including those unreachable from main
+        #[clap(long)]
+        keep_all: bool,
+        /// Set this flag to apply peephole optimisations to the generated code
+        #[clap(short = 'O', long)]
+        optimize: bool,
+        /// Target profile, a built-in name (bali-v1, bali-v1-long, bali-v1-nodiv) or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
     },
     /// Write Bali binary to serial Bali device
     Serial {
         /// Path of the binary file to transfer to Bali device
         #[clap(short, long)]
         bin: String,
-        /// Serial device identifier of target Bali device
+        /// Serial device identifier of target Bali device, selected by VID/PID if omitted
+        #[clap(short, long)]
+        device: Option<String>,
+        /// USB vendor ID (hex) used to select the device if no device is given
+        #[clap(long, parse(try_from_str = parse_hex))]
+        vid: Option<u16>,
+        /// USB product ID (hex) used to select the device if no device is given
+        #[clap(long, parse(try_from_str = parse_hex))]
+        pid: Option<u16>,
+        /// Set this flag if Bali device expects 16 bit program length, shorthand for --protocol length=16le
+        #[clap(short, long, conflicts_with = "protocol")]
+        long: bool,
+        /// UART protocol of the Bali firmware, e.g. length=16le,cycles=32be, defaults to the protocol of the target
         #[clap(short, long)]
-        device: String,
-        /// Set this flag if Bali device expects 16 bit program length
+        protocol: Option<Protocol>,
+        /// Target profile, a built-in name (bali-v1, bali-v1-long, bali-v1-nodiv) or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
+        /// Set this flag to dump all bytes received from the device
+        #[clap(long)]
+        raw_response: bool,
+        /// Result data to read back after execution (tos, output or mem:START:COUNT)
+        #[clap(short, long)]
+        readback: Option<Readback>,
+        /// Expected readback values, comma-separated
+        #[clap(short, long, use_value_delimiter = true, allow_hyphen_values = true)]
+        expect: Vec<i32>,
+        /// Set this flag to compare the readback values to a simulation of the binary instead of --expect
+        #[clap(short, long, conflicts_with = "expect")]
+        simulate: bool,
+        /// Set this flag to keep the port open after upload and print the bytes sent by the device
+        #[clap(short, long)]
+        monitor: bool,
+        /// Set this flag to print monitored bytes as hex instead of text
+        #[clap(long, requires = "monitor")]
+        hex: bool,
+        /// Byte sequence that ends monitoring, as text or as hex prefixed with 0x
+        #[clap(short, long, requires = "monitor")]
+        terminator: Option<Terminator>,
+    },
+    /// Print bytes sent by serial Bali device
+    Monitor {
+        /// Serial device identifier of target Bali device, selected by VID/PID if omitted
         #[clap(short, long)]
+        device: Option<String>,
+        /// USB vendor ID (hex) used to select the device if no device is given
+        #[clap(long, parse(try_from_str = parse_hex))]
+        vid: Option<u16>,
+        /// USB product ID (hex) used to select the device if no device is given
+        #[clap(long, parse(try_from_str = parse_hex))]
+        pid: Option<u16>,
+        /// Set this flag to print received bytes as hex instead of text
+        #[clap(long)]
+        hex: bool,
+        /// Byte sequence that ends monitoring, as text or as hex prefixed with 0x
+        #[clap(short, long)]
+        terminator: Option<Terminator>,
+    },
+    /// Simulate execution of Bali binary
+    Simulate {
+        /// Path of the binary file to simulate
+        #[clap(short, long)]
+        bin: String,
+        /// Result data to print after execution (tos, output or mem:START:COUNT)
+        #[clap(short, long)]
+        readback: Option<Readback>,
+        /// Target profile, a built-in name (bali-v1, bali-v1-long, bali-v1-nodiv) or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
+    },
+    /// Run test programs and compare their results to expected values
+    Test {
+        /// Directory containing the test class files and their `.expect` sidecar files
+        dir: String,
+        /// Serial device identifier of target Bali device, tests are simulated if omitted
+        #[clap(short, long)]
+        device: Option<String>,
+        /// Set this flag if Bali device expects 16 bit program length, shorthand for --protocol length=16le
+        #[clap(short, long, conflicts_with = "protocol")]
         long: bool,
+        /// UART protocol of the Bali firmware, e.g. length=16le,cycles=32be, defaults to the protocol of the target
+        #[clap(short, long)]
+        protocol: Option<Protocol>,
+        /// Target profile, a built-in name (bali-v1, bali-v1-long, bali-v1-nodiv) or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
+        /// Path of the JUnit XML report to write
+        #[clap(long)]
+        junit: Option<String>,
+        /// Path of the JSON report to write
+        #[clap(long)]
+        json: Option<String>,
+        /// Representation of float values, soft (IEEE-754 emulation) or fixed16 (Q16.16 fixed-point)
+        #[clap(long, default_value = "soft")]
+        float: FloatMode,
+        /// Set this flag to apply peephole optimisations to the generated code
+        #[clap(short = 'O', long)]
+        optimize: bool,
+    },
+    /// List available serial ports
+    Ports,
+    /// Remove debug attributes from JVM class file
+    Strip {
+        /// Path of the class file to strip
+        #[clap(short, long)]
+        classfile: String,
+        /// Path of the stripped class file
+        #[clap(short, long)]
+        output: String,
     },
     /// Verify JVM class file compatibility to Bali processor
     Verify {
         /// Path of the class file to verify
         #[clap(short, long)]
         classfile: String,
+        /// Representation of float values, soft (IEEE-754 emulation) or fixed16 (Q16.16 fixed-point)
+        #[clap(long, default_value = "soft")]
+        float: FloatMode,
+        /// Target profile, a built-in name (bali-v1, bali-v1-long, bali-v1-nodiv) or the path of a TOML file
+        #[clap(long, default_value = "bali-v1")]
+        target: Target,
+        /// Set this flag to keep all methods of the main class, including those unreachable from main
+        #[clap(long)]
+        keep_all: bool,
+        /// Set this flag to apply peephole optimisations to the generated code
+        #[clap(short = 'O', long)]
+        optimize: bool,
     },
 }
 
+fn parse_hex(value: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+}
+
+fn select_protocol(protocol: &Option<Protocol>, long: bool, target: &Target) -> Protocol {
+    let mut protocol = protocol.clone().unwrap_or_else(|| target.protocol.clone());
+    if long {
+        protocol.length_width = 2;
+    }
+
+    protocol
+}
+
+///
+/// Looks up a method of a class by its signature, or by its name if it is not overloaded.
+///
+fn find_method(class: &ClassFile, method: &str) -> Result<String, Error> {
+    let sigs: Vec<String> = codeblocks(class)?
+        .into_keys()
+        .filter(|sig| sig == method || sig.split('(').next() == Some(method))
+        .collect();
+
+    match &sigs[..] {
+        [sig] => Ok(sig.to_string()),
+        [] => Err(Error::other(format!("method {} not found", method))),
+        _ => Err(Error::other(format!(
+            "method name {} is ambiguous, give one of the signatures {}",
+            method,
+            sigs.join(", ")
+        ))),
+    }
+}
+
+fn read_source(dir: &Path, source: Option<String>) -> Vec<String> {
+    source
+        .and_then(|source| std::fs::read_to_string(dir.with_file_name(source)).ok())
+        .map(|content| content.lines().map(|l| l.to_string()).collect())
+        .unwrap_or_default()
+}
+
 fn read_binary(path: &str) -> Vec<u8> {
     let res = File::open(path);
     let mut buffer = Vec::new();
@@ -95,22 +340,43 @@ fn main() -> std::io::Result<()> {
         }
         Commands::Method { classfile } => {
             let classinfo = read_classfile(classfile)?;
-            for (name, code_info) in codeblocks(&classinfo) {
+            let source = read_source(Path::new(classfile), sourcefile(&classinfo)?);
+            for (name, code_info) in codeblocks(&classinfo)? {
                 if name == INIT_SIG {
                     continue;
                 }
                 let signature = methodstring(&name);
-                print_method(&classinfo, &signature, &code_info);
+                print_method(&classinfo, &signature, &code_info, &source);
             }
         }
-        Commands::Binary { classfile, output } => {
-            let classinfo = read_classfile(classfile)?;
-            let binary = binarygen(&classinfo);
+        Commands::Binary {
+            classfile,
+            output,
+            float,
+            target,
+            keep_all,
+            optimize,
+        } => {
+            let classes = read_classset(classfile)?;
+            let options = Options {
+                float: *float,
+                target: target.clone(),
+                keep_all: *keep_all,
+                optimize: *optimize,
+            };
+            let program = lower(&classes, &options)?;
+            if *optimize {
+                print!("{}", savingsoutput(&program.methods));
+            }
+            let (binary, debug) = binarygen_program(&classes, program, &options)?;
             let outpath = Path::new(&classfile).with_extension("bali.out");
             let mut buffer = File::create(outpath.to_str().unwrap())?;
 
             buffer.write_all(&binary)?;
 
+            let debugpath = Path::new(&classfile).with_extension("bali.dbg");
+            write_debuginfo(debugpath.to_str().unwrap(), &debug)?;
+
             if *output {
                 hexyl::Printer::new(
                     &mut std::io::stdout(),
@@ -122,9 +388,106 @@ fn main() -> std::io::Result<()> {
                 .unwrap();
             }
         }
-        Commands::Testfile { classfile } => {
-            let classinfo = read_classfile(classfile)?;
-            let binary = binarygen(&classinfo);
+        Commands::Callgraph {
+            classfile,
+            format,
+            float,
+            target,
+        } => {
+            let classes = read_classset(classfile)?;
+            let options = Options {
+                float: *float,
+                target: target.clone(),
+                keep_all: true,
+                ..Default::default()
+            };
+            let program = lower(&classes, &options)?;
+            let reached = reachable(&program.methods);
+
+            print!(
+                "{}",
+                callgraphoutput(classfile, &program.methods, &reached, *format)
+            );
+        }
+        Commands::Cfg {
+            classfile,
+            method,
+            format,
+            lowered,
+            optimize,
+            float,
+            target,
+        } => {
+            let classes = read_classset(classfile)?;
+            let sig = find_method(&classes[0], method)?;
+
+            let output = if *lowered {
+                let options = Options {
+                    float: *float,
+                    target: target.clone(),
+                    keep_all: true,
+                    optimize: *optimize,
+                };
+                let program = lower(&classes, &options)?;
+                let names: Vec<String> = program.methods.iter().map(|m| m.name.clone()).collect();
+                let method = program
+                    .methods
+                    .into_iter()
+                    .find(|m| m.name == sig)
+                    .ok_or_else(|| Error::other(format!("method {} is not emitted", sig)))?;
+
+                // local variable and line tables refer to the original bytecode
+                let mut code_info = method.code;
+                code_info.code = encode(&method.instrs?)?.0;
+                code_info.locals.clear();
+                code_info.lines.clear();
+
+                let methodname = |index: u16| names.get(index as usize).cloned();
+                let blocks = basic_blocks(&code_info.code)?;
+                cfgoutput(&sig, &code_info, &blocks, *format, &methodname)
+            } else {
+                let methodrefs = methodrefs(&classes[0]);
+                let methodname = |index: u16| methodrefs.get_by_left(&index).cloned();
+                let code_info = codeblocks(&classes[0])?.remove(&sig).unwrap();
+                let blocks = basic_blocks(&code_info.code)?;
+                cfgoutput(&sig, &code_info, &blocks, *format, &methodname)
+            };
+
+            print!("{}", output);
+        }
+        Commands::Disasm { bin, debug, target } => {
+            let binary = read_binary(bin);
+            let debugpath = match debug {
+                Some(debug) => debug.to_string(),
+                None => Path::new(bin)
+                    .with_extension("dbg")
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            };
+            let debug = read_debuginfo(&debugpath).ok();
+            let source = read_source(
+                Path::new(bin),
+                debug.as_ref().and_then(|d| d.source.clone()),
+            );
+
+            print_disasm(bin, &binary, &target.lut, debug.as_ref(), &source)?;
+        }
+        Commands::Testfile {
+            classfile,
+            target,
+            float,
+            keep_all,
+            optimize,
+        } => {
+            let classes = read_classset(classfile)?;
+            let options = Options {
+                target: target.clone(),
+                float: *float,
+                keep_all: *keep_all,
+                optimize: *optimize,
+            };
+            let binary = binarygen(&classes, &options)?;
             let outpath = Path::new(&classfile).with_extension("mem");
             let mut buffer = File::create(outpath.to_str().unwrap())?;
 
@@ -135,16 +498,203 @@ fn main() -> std::io::Result<()> {
 
             write!(buffer, "{}", output)?;
         }
-        Commands::Serial { bin, device, long } => {
+        Commands::Serial {
+            bin,
+            device,
+            vid,
+            pid,
+            long,
+            protocol,
+            raw_response,
+            readback,
+            expect,
+            simulate: compare_sim,
+            monitor: keep_open,
+            hex,
+            terminator,
+            target,
+        } => {
             let binary = read_binary(bin);
-            let mut port = open_serial(device);
+            let protocol = select_protocol(protocol, *long, target);
+            let device = match device {
+                Some(device) => device.to_string(),
+                None => find_device(*vid, *pid)?,
+            };
+            let mut port = open_serial(&device)?;
+            let mut received: Vec<u8> = Vec::new();
+
+            if *keep_open {
+                // the cycle count is consumed first, so it is not printed as device output
+                println!(
+                    "{}",
+                    binwrite(&mut port, &binary, &protocol, &mut received)?
+                );
+                monitor(&mut port, &mut std::io::stdout(), *hex, terminator.as_ref())?;
+                return Ok(());
+            }
 
-            if let Ok(turnaround) = binwrite(&mut port, &binary, *long) {
-                println!("{}", turnaround);
+            let result =
+                binwrite(&mut port, &binary, &protocol, &mut received).and_then(|turnaround| {
+                    println!("{}", turnaround);
+                    match readback {
+                        Some(request) => {
+                            uart::readback(&mut port, request, &protocol, &mut received)
+                                .map(|values| Some((request, values)))
+                        }
+                        None => Ok(None),
+                    }
+                });
+
+            if *raw_response {
+                hexyl::Printer::new(
+                    &mut std::io::stdout(),
+                    true,
+                    hexyl::BorderStyle::Ascii,
+                    true,
+                )
+                .print_all(std::io::Cursor::new(&received))
+                .unwrap();
             }
+
+            if let Some((request, values)) = result? {
+                for value in &values {
+                    println!("{}", value);
+                }
+
+                let expected = if *compare_sim {
+                    Some(simulate(&binary, &target.lut)?.readback(request)?)
+                } else if !expect.is_empty() {
+                    Some(expect.to_vec())
+                } else {
+                    None
+                };
+
+                if let Some(expected) = expected {
+                    if values != expected {
+                        return Err(Error::other(format!(
+                            "readback mismatch: expected {:?}, received {:?}",
+                            expected, values
+                        )));
+                    }
+                }
+            }
+        }
+        Commands::Monitor {
+            device,
+            vid,
+            pid,
+            hex,
+            terminator,
+        } => {
+            let device = match device {
+                Some(device) => device.to_string(),
+                None => find_device(*vid, *pid)?,
+            };
+            let mut port = open_serial(&device)?;
+
+            monitor(&mut port, &mut std::io::stdout(), *hex, terminator.as_ref())?;
         }
-        Commands::Verify { classfile } => {
-            let _classinfo = read_classfile(classfile);
+        Commands::Simulate {
+            bin,
+            readback,
+            target,
+        } => {
+            let binary = read_binary(bin);
+            let result = simulate(&binary, &target.lut)?;
+            println!("{}", result.cycles);
+
+            if let Some(request) = readback {
+                for value in result.readback(request)? {
+                    println!("{}", value);
+                }
+            }
+        }
+        Commands::Test {
+            dir,
+            device,
+            long,
+            protocol,
+            junit,
+            json,
+            target,
+            optimize,
+            float,
+        } => {
+            let protocol = select_protocol(protocol, *long, target);
+            let options = Options {
+                target: target.clone(),
+                float: *float,
+                optimize: *optimize,
+                ..Default::default()
+            };
+            let results = run_tests(Path::new(dir), device.as_deref(), &options, &protocol)?;
+
+            for result in &results {
+                match (&result.failure, result.cycles) {
+                    (None, Some(cycles)) => println!("PASS {} ({} cycles)", result.name, cycles),
+                    (None, None) => println!("PASS {}", result.name),
+                    (Some(failure), _) => println!("FAIL {}: {}", result.name, failure),
+                }
+            }
+
+            if let Some(path) = junit {
+                std::fs::write(path, junit_report(&results))?;
+            }
+            if let Some(path) = json {
+                std::fs::write(path, json_report(&results))?;
+            }
+
+            let failures = results.iter().filter(|r| !r.passed).count();
+            if failures > 0 {
+                return Err(Error::other(format!(
+                    "{} of {} tests failed",
+                    failures,
+                    results.len()
+                )));
+            }
+        }
+        Commands::Ports => {
+            for port in available_ports() {
+                println!("{}", portstring(&port));
+            }
+        }
+        Commands::Strip { classfile, output } => {
+            let mut classinfo = read_classfile(classfile)?;
+            classinfo.strip_debug()?;
+            write_classfile(output, &mut classinfo)?;
+        }
+        Commands::Verify {
+            classfile,
+            float,
+            target,
+            keep_all,
+            optimize,
+        } => {
+            let classes = read_classset(classfile)?;
+            let options = Options {
+                float: *float,
+                target: target.clone(),
+                keep_all: *keep_all,
+                optimize: *optimize,
+            };
+            let report = verify(&classes, &options);
+
+            let warnings = report.warnings.iter().map(|d| (d, "warning: "));
+            let diagnostics = report.notes.iter().map(|d| (d, "")).chain(warnings);
+            for (diagnostic, prefix) in diagnostics.chain(report.issues.iter().map(|d| (d, ""))) {
+                if diagnostic.method.is_empty() {
+                    println!("{}{}", prefix, diagnostic.message);
+                } else {
+                    println!("{}{}: {}", prefix, diagnostic.method, diagnostic.message);
+                }
+            }
+
+            if !report.issues.is_empty() {
+                return Err(Error::other(format!(
+                    "{} compatibility issues found",
+                    report.issues.len()
+                )));
+            }
         }
     };
 
@@ -1,157 +1,841 @@
+use crate::debug::{DebugInfo, LineEntry, LocalEntry, MethodDebugInfo};
+use crate::descriptor::FieldType;
+use crate::emulate::{intrinsic, long_result, lower_float, lower_long, FloatMode};
+use crate::ir::{decode, encode, expand, Instr, LutRef};
 use crate::opcodes::opmap;
-use crate::structs::ClassFile;
-use crate::structs::{codeblocks, constants, methodrefs, ConstPoolValue};
-
-use bimap::BiBTreeMap;
+use crate::optimize::{optimize, Savings};
+use crate::runtime::{helper, runtime_class, RUNTIME_CLASS};
+use crate::structs::{
+    classname, codeblocks, constants, fieldref, instance_fields, methodref, sourcefile,
+    static_fields, super_class, this_class, BaliCode, ClassFile, ConstPoolValue, ACC_FINAL,
+    ACC_PRIVATE,
+};
+use crate::target::Target;
 
 use std::collections::btree_map::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::io::Error;
 
 pub const INIT_SIG: &str = "<init>()V";
 pub const MAIN_SIG: &str = "main([Ljava/lang/String;)V";
+pub const CLINIT_SIG: &str = "<clinit>()V";
+const OBJECT_CLASS: &str = "java/lang/Object";
 
-const LUTENTRY: usize = 4;
+/// Size of a method or constant LUT entry in bytes.
+pub const LUTENTRY: usize = 4;
 
-fn memlayout(classinfo: &ClassFile) -> (BiBTreeMap<u16, String>, u16) {
-    let codeblocks = codeblocks(classinfo);
+const ICONST_0: u8 = 0x03;
+const IALOAD: u8 = 0x2e;
+const IASTORE: u8 = 0x4f;
+const POP: u8 = 0x57;
+const SWAP: u8 = 0x5f;
+const NEWARRAY: u8 = 0xbc;
+/// Array type operand of `newarray` for int arrays.
+const T_INT: u8 = 10;
+/// Array type operand of `newarray` for float arrays.
+const T_FLOAT: u8 = 6;
+/// Array type operand of `newarray` for double arrays.
+const T_DOUBLE: u8 = 7;
+/// Array type operand of `newarray` for long arrays.
+const T_LONG: u8 = 11;
 
-    let maincode = &codeblocks[MAIN_SIG];
-    let mainsize: u16 = maincode.code.len() as u16;
+/// Options controlling the lowering of a class set.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Representation of `float` values.
+    pub float: FloatMode,
+    /// Hardware revision the program is lowered for.
+    pub target: Target,
+    /// Keep all methods of the main class, including those unreachable from `main`.
+    pub keep_all: bool,
+    /// Apply peephole optimisations to the lowered instructions.
+    pub optimize: bool,
+}
 
-    let mut currentaddr: u16 = 0;
-    let mut methodaddrs: BiBTreeMap<u16, String> = BiBTreeMap::new();
+/// Method of the input class set, with its bytecode lowered to instructions supported by Bali.
+pub struct LoweredMethod {
+    /// Method signature, prefixed with the class name for methods outside the main class.
+    pub name: String,
+    /// Index of the class declaring the method within the class set, the runtime helper class comes last.
+    pub class: usize,
+    pub code: BaliCode,
+    /// Lowered instructions, or the reason the method cannot be run on Bali.
+    pub instrs: Result<Vec<Instr>, Error>,
+    /// Methods invoked by the lowered instructions, including runtime helper methods.
+    pub callees: Vec<String>,
+    /// Savings of the peephole optimiser, empty if optimisation is disabled.
+    pub savings: Savings,
+}
 
-    methodaddrs.insert(currentaddr, MAIN_SIG.to_string());
-    currentaddr += mainsize;
+/// Lowered methods of a class set in method LUT order, along with the integer constants of the constant LUT.
+pub struct Program {
+    pub methods: Vec<LoweredMethod>,
+    pub consts: Vec<i32>,
+}
 
-    for (name, codeblock) in codeblocks {
-        // main is already in the map, <init> ignored
-        if name == MAIN_SIG || name == INIT_SIG {
-            continue;
+/// Class set along with the information needed to resolve references between its classes.
+struct Linker<'a> {
+    classes: Vec<&'a ClassFile>,
+    names: Vec<String>,
+    codeblocks: Vec<BTreeMap<String, BaliCode>>,
+}
+
+impl<'a> Linker<'a> {
+    fn new(classes: Vec<&'a ClassFile>) -> Result<Self, Error> {
+        Ok(Linker {
+            names: classes.iter().map(|c| this_class(c)).collect(),
+            codeblocks: classes
+                .iter()
+                .map(|c| codeblocks(c))
+                .collect::<Result<_, Error>>()?,
+            classes,
+        })
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    fn methodkey(&self, class: usize, sig: &str) -> String {
+        if class == 0 {
+            sig.to_string()
+        } else {
+            format!("{}.{}", self.names[class], sig)
         }
+    }
+
+    ///
+    /// Lists the fields of objects of the given class, given by name and descriptor,
+    /// with the fields of its superclasses first.
+    ///
+    /// Returns an error if a superclass is not part of the class set or a field type is not supported.
+    ///
+    fn fields(&self, class: usize) -> Result<Vec<(String, String)>, Error> {
+        let mut fields = match super_class(self.classes[class]) {
+            Some(name) if name != OBJECT_CLASS => {
+                let superclass = self.position(&name).ok_or_else(|| {
+                    Error::other(format!("class {} is not part of the input classes", name))
+                })?;
+                self.fields(superclass)?
+            }
+            _ => Vec::new(),
+        };
 
-        let codesize: u16 = codeblock.code.len() as u16;
-        methodaddrs.insert(currentaddr, name);
-        currentaddr += codesize;
+        for (name, descriptor) in instance_fields(self.classes[class]) {
+            let fieldtype: FieldType = descriptor.parse()?;
+            if matches!(fieldtype, FieldType::Long | FieldType::Double) {
+                return Err(Error::other(format!(
+                    "field {}.{} of type {} is not supported",
+                    self.names[class], name, fieldtype
+                )));
+            }
+            fields.push((name, descriptor));
+        }
+
+        Ok(fields)
     }
 
-    (methodaddrs, currentaddr)
-}
+    ///
+    /// Computes the offset of a field within the object array, starting the field lookup
+    /// at the given class and continuing with its superclasses.
+    ///
+    fn field_offset(&self, class: &str, field: &str) -> Result<usize, Error> {
+        let error = || {
+            Error::other(format!(
+                "field {}.{} is not part of the input classes",
+                class, field
+            ))
+        };
+        let mut current = self.position(class).ok_or_else(error)?;
 
-fn luts(classinfo: &ClassFile) -> (Vec<u8>, BTreeMap<u16, u16>, BTreeMap<String, u16>) {
-    let codeblocks = codeblocks(classinfo);
-    let (memlayout, _) = memlayout(classinfo);
+        loop {
+            let fields = self.fields(current)?;
+            let own = instance_fields(self.classes[current]).len();
+            if let Some(offset) = fields[fields.len() - own..]
+                .iter()
+                .position(|(name, _)| name == field)
+            {
+                return Ok(fields.len() - own + offset);
+            }
+            current = super_class(self.classes[current])
+                .and_then(|name| self.position(&name))
+                .ok_or_else(error)?;
+        }
+    }
 
-    let ints: BTreeMap<u16, i32> = constants(classinfo)
-        .into_iter()
-        .filter(|(_, v)| matches!(v, ConstPoolValue::Integer(_)))
-        .map(|(k, v)| {
-            if let ConstPoolValue::Integer(value) = v {
-                (k, value)
-            } else {
-                (0, 0)
+    fn is_subclass(&self, class: usize, ancestor: usize) -> bool {
+        let mut current = super_class(self.classes[class]);
+        while let Some(name) = current {
+            match self.position(&name) {
+                Some(superclass) if superclass == ancestor => return true,
+                Some(superclass) => current = super_class(self.classes[superclass]),
+                None => return false,
             }
-        })
-        .collect();
+        }
+        false
+    }
 
-    let method_entry_count: usize = memlayout.len();
-    let consts_entry_count: usize = ints.len();
-    let lutsize: usize = LUTENTRY * method_entry_count + LUTENTRY * consts_entry_count;
+    ///
+    /// Looks up the class declaring the method with the given signature,
+    /// starting at the given class and continuing with its superclasses within the class set.
+    ///
+    fn resolve(&self, class: &str, sig: &str) -> Option<usize> {
+        let mut current = self.position(class)?;
+        while !self.codeblocks[current].contains_key(sig) {
+            current = self.position(&super_class(self.classes[current])?)?;
+        }
+        Some(current)
+    }
 
-    let mut methodlut: Vec<u8> = Vec::with_capacity(lutsize);
-    let mut nameindex: BTreeMap<String, u16> = BTreeMap::new();
-    for (i, (methodaddr, methodname)) in memlayout.into_iter().enumerate() {
-        methodlut.push(((lutsize as u16 + methodaddr as u16) >> 8) as u8);
-        methodlut.push(((lutsize as u16 + methodaddr as u16) & 0xff) as u8);
-        methodlut.push(if methodname == MAIN_SIG {
-            0x00_u8
-        } else {
-            codeblocks[&methodname].argcount as u8
-        });
-        methodlut.push(codeblocks[&methodname].max_locals.try_into().unwrap());
-        nameindex.insert(methodname, i.try_into().unwrap());
+    ///
+    /// Looks up a class within the class set that overrides the method with the given signature
+    /// declared in the given class, which prevents devirtualizing calls to the method.
+    ///
+    fn overriding_class(&self, class: usize, sig: &str) -> Option<&str> {
+        let flags = self.codeblocks[class][sig].access_flags;
+        if flags & (ACC_PRIVATE | ACC_FINAL) != 0 {
+            return None;
+        }
+
+        (0..self.classes.len())
+            .find(|sub| self.codeblocks[*sub].contains_key(sig) && self.is_subclass(*sub, class))
+            .map(|sub| self.names[sub].as_str())
     }
+}
 
-    let mut constmap: BTreeMap<u16, u16> = BTreeMap::new();
-    let mut memindex = method_entry_count as u8;
-    for (poolindex, intvalue) in &ints {
-        methodlut.push((intvalue >> 24) as u8);
-        methodlut.push((intvalue >> 16) as u8);
-        methodlut.push((intvalue >> 8) as u8);
-        methodlut.push(*intvalue as u8);
+///
+/// Rewrites a method invocation into an `invokestatic` of the method LUT entry of the invoked method.
+///
+/// Instance methods are devirtualized, with `this` passed as an ordinary argument.
+/// Invocations of the `java.lang.Object` constructor only discard the object reference.
+///
+fn lower_invoke(
+    linker: &Linker,
+    class: usize,
+    instr: &mut Instr,
+    mnemonic: &str,
+) -> Result<(), Error> {
+    let index = u16::from_be_bytes([instr.operands[0], instr.operands[1]]);
+    let (name, sig) = methodref(linker.classes[class], index)
+        .ok_or_else(|| Error::other(format!("invalid method reference #{}", index)))?;
 
-        constmap.insert(*poolindex, memindex.into());
-        memindex += 1;
+    if mnemonic == "invokespecial" && name == OBJECT_CLASS && sig == INIT_SIG {
+        instr.opcode = POP;
+        instr.operands = Vec::new();
+        return Ok(());
     }
 
-    (methodlut, constmap, nameindex)
+    let target = linker.resolve(&name, &sig).ok_or_else(|| {
+        Error::other(format!(
+            "method {}.{} is not part of the input classes",
+            name, sig
+        ))
+    })?;
+
+    if mnemonic == "invokevirtual" {
+        if let Some(sub) = linker.overriding_class(target, &sig) {
+            return Err(Error::other(format!(
+                "call of {}.{} cannot be devirtualized, it is overridden in {}",
+                name, sig, sub
+            )));
+        }
+    }
+
+    *instr = Instr {
+        pc: instr.pc,
+        line: instr.line,
+        ..Instr::call(&linker.methodkey(target, &sig))
+    };
+
+    Ok(())
 }
 
 ///
-/// Generate binary stream to write to Bali processor program memory.
+/// Rewrites an object allocation into the allocation of an int array with one element per field.
 ///
-/// Returns byte vector for writing to output file.
+/// Objects of classes without fields carry no state and are allocated as empty arrays,
+/// so they still have a distinct non-null reference.
 ///
-pub fn binarygen(classinfo: &ClassFile) -> Vec<u8> {
-    let methodrefs = methodrefs(classinfo);
-    let (memlayout, codesize) = memlayout(classinfo);
-    let (mut methodlut, intrefs, methodaddrs) = luts(classinfo);
-    let opmap = opmap();
+fn lower_new(linker: &Linker, class: usize, instr: &Instr) -> Result<Vec<Instr>, Error> {
+    let index = u16::from_be_bytes([instr.operands[0], instr.operands[1]]);
+    let name = classname(linker.classes[class], index)
+        .ok_or_else(|| Error::other(format!("invalid class reference #{}", index)))?;
+    let newclass = linker
+        .position(&name)
+        .ok_or_else(|| Error::other(format!("class {} is not part of the input classes", name)))?;
 
-    // map method reference index to Bali program memory address
-    let mut refaddr: HashMap<u16, u16> = HashMap::new();
-    for (methodref, methodname) in methodrefs {
-        if methodname == INIT_SIG {
-            continue;
+    let fields = linker.fields(newclass)?;
+    Ok(vec![
+        Instr::push(fields.len() as i32),
+        Instr::new(NEWARRAY, vec![T_INT]),
+    ])
+}
+
+///
+/// Rewrites a field access into an array access of the object array at the field offset.
+///
+fn lower_field(
+    linker: &Linker,
+    class: usize,
+    instr: &Instr,
+    mnemonic: &str,
+) -> Result<Vec<Instr>, Error> {
+    let index = u16::from_be_bytes([instr.operands[0], instr.operands[1]]);
+    let (name, field, _) = fieldref(linker.classes[class], index)
+        .ok_or_else(|| Error::other(format!("invalid field reference #{}", index)))?;
+    let offset = Instr::push(linker.field_offset(&name, &field)? as i32);
+
+    Ok(match mnemonic {
+        "getfield" => vec![offset, Instr::new(IALOAD, Vec::new())],
+        _ => vec![
+            offset,
+            Instr::new(SWAP, Vec::new()),
+            Instr::new(IASTORE, Vec::new()),
+        ],
+    })
+}
+
+///
+/// Rewrites a static field access into an array access of the statics array at address 0.
+///
+/// Only the runtime helper class may have static fields, which are allocated by the prologue of `main`
+/// as the first array of the program, at address 0 on every target (see `Target`).
+///
+fn lower_static(
+    linker: &Linker,
+    class: usize,
+    instr: &Instr,
+    mnemonic: &str,
+) -> Result<Vec<Instr>, Error> {
+    let index = u16::from_be_bytes([instr.operands[0], instr.operands[1]]);
+    let (name, field, _) = fieldref(linker.classes[class], index)
+        .ok_or_else(|| Error::other(format!("invalid field reference #{}", index)))?;
+    if name != RUNTIME_CLASS {
+        return Err(Error::other(format!(
+            "field {}.{} is static, static fields are not supported",
+            name, field
+        )));
+    }
+
+    let statics = static_fields(linker.classes[class]);
+    let offset = statics
+        .iter()
+        .position(|(name, _)| *name == field)
+        .ok_or_else(|| Error::other(format!("field {}.{} does not exist", name, field)))?;
+
+    Ok(match mnemonic {
+        "getstatic" => vec![
+            Instr::new(ICONST_0, Vec::new()),
+            Instr::push(offset as i32),
+            Instr::new(IALOAD, Vec::new()),
+        ],
+        _ => vec![
+            Instr::new(ICONST_0, Vec::new()),
+            Instr::new(SWAP, Vec::new()),
+            Instr::push(offset as i32),
+            Instr::new(SWAP, Vec::new()),
+            Instr::new(IASTORE, Vec::new()),
+        ],
+    })
+}
+
+///
+/// Rewrites an array allocation of reference type into an int array allocation, as references are ints,
+/// and multi-dimensional array allocations into calls of runtime helpers allocating nested arrays.
+///
+fn lower_array(
+    linker: &Linker,
+    class: usize,
+    instr: &Instr,
+    mnemonic: &str,
+) -> Result<Vec<Instr>, Error> {
+    if mnemonic == "anewarray" {
+        return Ok(vec![Instr::new(NEWARRAY, vec![T_INT])]);
+    }
+
+    let index = u16::from_be_bytes([instr.operands[0], instr.operands[1]]);
+    let descriptor = classname(linker.classes[class], index)
+        .ok_or_else(|| Error::other(format!("invalid class reference #{}", index)))?;
+    let dimensions = instr.operands[2];
+
+    let mut element: FieldType = descriptor.parse()?;
+    for _ in 0..dimensions {
+        if let FieldType::Array(inner) = element {
+            element = *inner;
         }
-        refaddr.insert(methodref, methodaddrs[&methodname]);
+    }
+    if matches!(element, FieldType::Long | FieldType::Double) {
+        return Err(Error::other(format!(
+            "arrays of {} elements are not supported",
+            element
+        )));
     }
 
-    // replace invokestatic address arguments with Bali memory addresses
-    let codeblocks = codeblocks(classinfo);
-    let memsize = methodlut.len() as u16 + codesize;
-    let mut mem = Vec::with_capacity(memsize.into());
-    mem.append(&mut methodlut);
+    match dimensions {
+        2 => Ok(vec![Instr::call(&helper("newarray2(II)[[I"))]),
+        3 => Ok(vec![Instr::call(&helper("newarray3(III)[[[I"))]),
+        _ => Err(Error::other(format!(
+            "allocation of {} with {} dimensions is not supported, at most 3 dimensions are",
+            descriptor, dimensions
+        ))),
+    }
+}
 
-    for (_, methodname) in memlayout {
-        if methodname == INIT_SIG {
-            continue;
+///
+/// Lowers the bytecode of a method to instructions supported by Bali.
+///
+/// Method invocations and `ldc` operands refer to LUT entries through `Instr::lutref`,
+/// the operands are only filled in once the LUT layout is known.
+///
+fn lower_method(
+    linker: &Linker,
+    class: usize,
+    sig: &str,
+    options: &Options,
+) -> Result<Vec<Instr>, Error> {
+    let codeblock = &linker.codeblocks[class][sig];
+    let constpool = constants(linker.classes[class]);
+    let opmap = options.target.opmap();
+    let instrs = decode(&codeblock.code, &codeblock.lines)?;
+
+    let lower_instr = |mut instr: Instr| -> Result<Vec<Instr>, Error> {
+        let mnemonic = opmap[&instr.opcode].mnemonic.as_str();
+
+        if let Some(instrs) = lower_long(mnemonic, &instr, &constpool)
+            .or_else(|| lower_float(mnemonic, &instr, &constpool, options.float))
+        {
+            return instrs;
+        }
+
+        match mnemonic {
+            "invokestatic" | "invokespecial" | "invokevirtual" => {
+                lower_invoke(linker, class, &mut instr, mnemonic)?;
+                // methods returning a long pass the high word through the runtime helper class
+                if matches!(&instr.lutref, Some(LutRef::Method(key)) if key.ends_with(")J")) {
+                    let mut instrs = vec![instr];
+                    instrs.extend(long_result());
+                    return Ok(instrs);
+                }
+            }
+            "ldc" => {
+                let index = instr.operands[0] as u16;
+                match constpool.get(&index) {
+                    Some(ConstPoolValue::Integer(value)) => {
+                        instr.lutref = Some(LutRef::Constant(*value));
+                    }
+                    _ => {
+                        return Err(Error::other(format!(
+                            "constant #{} is not an integer constant",
+                            index
+                        )))
+                    }
+                }
+            }
+            "newarray" if matches!(instr.operands[0], T_LONG | T_DOUBLE) => {
+                return Err(Error::other(
+                    "arrays of long or double elements are not supported",
+                ))
+            }
+            // float arrays hold the bit patterns of their elements
+            "newarray" if instr.operands[0] == T_FLOAT => instr.operands = vec![T_INT],
+            "new" => return lower_new(linker, class, &instr),
+            "getfield" | "putfield" => return lower_field(linker, class, &instr, mnemonic),
+            "getstatic" | "putstatic" => return lower_static(linker, class, &instr, mnemonic),
+            "anewarray" | "multianewarray" => return lower_array(linker, class, &instr, mnemonic),
+            "return" if class == 0 && sig == MAIN_SIG => {
+                instr.opcode = 0xFF_u8; // halt
+            }
+            _ => {}
         }
 
-        let code_old = codeblocks[&methodname].code.to_vec();
-        let mut code_new = codeblocks[&methodname].code.to_vec();
+        Ok(vec![instr])
+    };
+
+    expand(instrs, |instr| {
+        let mnemonic = opmap[&instr.opcode].mnemonic.to_string();
+        let mut instrs = lower_instr(instr)?;
 
-        let mut argcount = 0;
-        for (i, opcode) in code_old.iter().enumerate() {
-            // skip arguments in parsing opcodes
-            if argcount > 0 {
-                argcount -= 1;
+        for instr in instrs.iter_mut() {
+            let lowered = &opmap[&instr.opcode];
+            if lowered.supported_on_bali {
                 continue;
             }
+            // instructions missing from the target fall back to runtime helpers
+            if let Some(call) = intrinsic(&lowered.mnemonic) {
+                *instr = call;
+                continue;
+            }
+            let reason = if lowered.mnemonic == mnemonic {
+                format!("instruction {} is not supported", mnemonic)
+            } else {
+                format!(
+                    "instruction {} is lowered to {}, which is not supported",
+                    mnemonic, lowered.mnemonic
+                )
+            };
+            return Err(Error::other(format!(
+                "{} by target {}",
+                reason, options.target.name
+            )));
+        }
 
-            let op = &opmap[opcode];
-            argcount = op.args;
+        Ok(instrs)
+    })
+}
 
-            if op.mnemonic == "invokestatic" {
-                let oldref = ((code_old[i + 1] as u16) << 8) | code_old[i + 2] as u16;
-                let newref = refaddr[&oldref] as u16;
-                code_new[i + 1] = (newref >> 8) as u8;
-                code_new[i + 2] = (newref & 0xff) as u8;
+///
+/// Fills in the operands of instructions referring to LUT entries with the corresponding LUT indices.
+///
+fn link(
+    instrs: &mut [Instr],
+    methodindex: &BTreeMap<String, u16>,
+    constindex: &HashMap<i32, u16>,
+) -> Result<(), Error> {
+    for instr in instrs.iter_mut() {
+        match &instr.lutref {
+            Some(LutRef::Method(key)) => {
+                let lutindex = methodindex
+                    .get(key)
+                    .ok_or_else(|| Error::other(format!("method {} is not emitted", key)))?;
+                instr.operands = lutindex.to_be_bytes().to_vec();
+            }
+            Some(LutRef::Constant(value)) => {
+                let lutindex = u8::try_from(constindex[value]).map_err(|_| {
+                    Error::other(format!("constant {} exceeds the LUT range of ldc", value))
+                })?;
+                instr.operands = vec![lutindex];
+            }
+            None => {}
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Lowers the methods of a class set to instructions supported by Bali.
+///
+/// The first class of the set is the main class, whose `main` method comes first in the method LUT,
+/// followed by all other methods sorted by name. Only methods reachable from `main` and the static initializer
+/// of the main class are included, unless `keep_all` is set, which keeps all methods of the main class except constructors.
+/// Returns an error if the main class has no `main` method.
+///
+pub fn lower(classes: &[ClassFile], options: &Options) -> Result<Program, Error> {
+    let runtime = runtime_class();
+    let linker = Linker::new(classes.iter().chain(std::iter::once(&runtime)).collect())?;
+
+    if !linker.codeblocks[0].contains_key(MAIN_SIG) {
+        return Err(Error::other(format!(
+            "class {} has no main method",
+            linker.names[0]
+        )));
+    }
+
+    let mut methods: BTreeMap<String, (usize, String)> = BTreeMap::new();
+    for (class, codeblocks) in linker.codeblocks.iter().enumerate() {
+        for sig in codeblocks.keys() {
+            methods.insert(linker.methodkey(class, sig), (class, sig.to_string()));
+        }
+    }
+
+    // lower all methods reachable from the roots
+    let mut worklist: Vec<String> = linker.codeblocks[0]
+        .keys()
+        .filter(|sig| match options.keep_all {
+            true => !sig.starts_with("<init>"),
+            false => *sig == MAIN_SIG || *sig == CLINIT_SIG,
+        })
+        .cloned()
+        .collect();
+    let mut lowered: BTreeMap<String, Result<Vec<Instr>, Error>> = BTreeMap::new();
+    let mut calls: HashMap<String, Vec<String>> = HashMap::new();
+    let mut savings: HashMap<String, Savings> = HashMap::new();
+    while let Some(key) = worklist.pop() {
+        if lowered.contains_key(&key) {
+            continue;
+        }
+        let (class, sig) = &methods[&key];
+        let instrs = lower_method(&linker, *class, sig, options).map(|instrs| {
+            if !options.optimize {
+                return instrs;
+            }
+            let (instrs, saved) = optimize(instrs);
+            savings.insert(key.to_string(), saved);
+            instrs
+        });
+        let mut callees: Vec<String> = Vec::new();
+        for instr in instrs.iter().flatten() {
+            if let Some(LutRef::Method(callee)) = &instr.lutref {
+                if methods.contains_key(callee) && !callees.contains(callee) {
+                    callees.push(callee.to_string());
+                }
+            }
+        }
+        worklist.extend(callees.iter().cloned());
+        calls.insert(key.to_string(), callees);
+        lowered.insert(key, instrs);
+    }
+
+    // reserve address 0, the null reference, before the first allocation of the program, with the reserved block
+    // holding the static fields of the runtime helper class if a linked helper uses them
+    let runtime = linker.classes.len() - 1;
+    let opmap = opmap();
+    let statics = lowered.keys().any(|key| {
+        let (class, sig) = &methods[key];
+        *class == runtime
+            && decode(&linker.codeblocks[*class][sig].code, &[])
+                .iter()
+                .flatten()
+                .any(|instr| {
+                    matches!(
+                        opmap[&instr.opcode].mnemonic.as_str(),
+                        "getstatic" | "putstatic"
+                    )
+                })
+    });
+    let allocates = lowered
+        .values()
+        .flatten()
+        .flatten()
+        .any(|instr| instr.opcode == NEWARRAY);
+    if statics || allocates {
+        if let Some(Ok(instrs)) = lowered.get_mut(MAIN_SIG) {
+            let count = match statics {
+                true => static_fields(linker.classes[runtime]).len(),
+                false => 0,
+            };
+            let prologue = vec![
+                Instr::push(count as i32),
+                Instr::new(NEWARRAY, vec![T_INT]),
+                Instr::new(POP, Vec::new()),
+            ];
+            for instr in instrs.iter_mut() {
+                instr.target = instr.target.map(|target| target + prologue.len());
+            }
+            instrs.splice(0..0, prologue);
+        }
+    }
+
+    let mut layout: Vec<(String, Result<Vec<Instr>, Error>)> = Vec::new();
+    layout.push((MAIN_SIG.to_string(), lowered.remove(MAIN_SIG).unwrap()));
+    layout.extend(lowered);
+
+    let methodindex: BTreeMap<String, u16> = layout
+        .iter()
+        .enumerate()
+        .map(|(i, (key, _))| (key.to_string(), i as u16))
+        .collect();
+
+    // integer constants of the main class in constant pool order, followed by the other constants used,
+    // omitting constants of the main class only used by eliminated methods
+    let mut consts: Vec<i32> = Vec::new();
+    let used: Vec<i32> = layout
+        .iter()
+        .filter_map(|(_, instrs)| instrs.as_ref().ok())
+        .flatten()
+        .filter_map(|instr| match instr.lutref {
+            Some(LutRef::Constant(value)) => Some(value),
+            _ => None,
+        })
+        .collect();
+    let pool = constants(&classes[0])
+        .into_values()
+        .filter_map(|value| match value {
+            ConstPoolValue::Integer(value) => Some(value),
+            _ => None,
+        })
+        .filter(|value| options.keep_all || used.contains(value));
+    for value in pool.chain(used.iter().copied()) {
+        if !consts.contains(&value) {
+            consts.push(value);
+        }
+    }
+    let constindex: HashMap<i32, u16> = consts
+        .iter()
+        .enumerate()
+        .map(|(i, value)| (*value, (layout.len() + i) as u16))
+        .collect();
+
+    let mut codeblocks = linker.codeblocks;
+    let methods = layout
+        .into_iter()
+        .map(|(name, instrs)| {
+            let (class, sig) = &methods[&name];
+            let instrs = instrs.and_then(|mut instrs| {
+                link(&mut instrs, &methodindex, &constindex)?;
+                Ok(instrs)
+            });
+            LoweredMethod {
+                code: codeblocks[*class].remove(sig).unwrap(),
+                class: *class,
+                callees: calls.remove(&name).unwrap_or_default(),
+                savings: savings.remove(&name).unwrap_or_default(),
+                name,
+                instrs,
             }
+        })
+        .collect();
+
+    Ok(Program { methods, consts })
+}
+
+///
+/// Determines the lowered methods reachable from `main` and the static initializer of the main class.
+///
+pub fn reachable(methods: &[LoweredMethod]) -> BTreeSet<String> {
+    let callees: HashMap<&str, &Vec<String>> = methods
+        .iter()
+        .map(|method| (method.name.as_str(), &method.callees))
+        .collect();
+
+    let mut reached: BTreeSet<String> = BTreeSet::new();
+    let mut worklist: Vec<&str> = vec![MAIN_SIG, CLINIT_SIG];
+    while let Some(name) = worklist.pop() {
+        if let Some(calls) = callees.get(name) {
+            if reached.insert(name.to_string()) {
+                worklist.extend(calls.iter().map(|callee| callee.as_str()));
+            }
+        }
+    }
+
+    reached
+}
+
+///
+/// Generate binary stream to write to Bali processor program memory.
+///
+/// The first class of the set is the main class, the others are classes used by the main class.
+/// Returns byte vector for writing to output file, or an error if a method cannot be run on Bali.
+///
+pub fn binarygen(classes: &[ClassFile], options: &Options) -> Result<Vec<u8>, Error> {
+    Ok(binarygen_debug(classes, options)?.0)
+}
+
+///
+/// Generate binary stream to write to Bali processor program memory, along with its debug information.
+///
+/// Returns byte vector for writing to output file and the mapping of program memory addresses
+/// to methods and source lines, or an error if a method cannot be run on Bali.
+///
+pub fn binarygen_debug(
+    classes: &[ClassFile],
+    options: &Options,
+) -> Result<(Vec<u8>, DebugInfo), Error> {
+    binarygen_program(classes, lower(classes, options)?, options)
+}
+
+///
+/// Generate binary stream and debug information from a program already lowered from the given classes
+/// with the given options, e.g. to report the optimiser savings without lowering the classes twice.
+///
+pub fn binarygen_program(
+    classes: &[ClassFile],
+    program: Program,
+    options: &Options,
+) -> Result<(Vec<u8>, DebugInfo), Error> {
+    let lutsize = LUTENTRY * (program.methods.len() + program.consts.len());
+
+    let mut methodlut: Vec<u8> = Vec::with_capacity(lutsize);
+    let mut code: Vec<u8> = Vec::new();
+
+    let mut debug = DebugInfo {
+        source: sourcefile(&classes[0])?,
+        methods: Vec::new(),
+    };
+
+    for method in program.methods {
+        let error = |err: Error| Error::other(format!("method {}: {}", method.name, err));
+        let codeblock = &method.code;
+        codeblock.validate(&options.target.lut).map_err(error)?;
+        let instrs = method.instrs.map_err(error)?;
+
+        let (mut code_new, addrs) = encode(&instrs).map_err(error)?;
+        let methodaddr = lutsize + code.len();
+        let argcount = if method.name == MAIN_SIG {
+            0
+        } else {
+            codeblock.argcount as usize
+        };
+
+        methodlut.extend_from_slice(
+            &options
+                .target
+                .lut
+                .pack(methodaddr, argcount, codeblock.max_locals as usize)
+                .map_err(error)?,
+        );
+        // LUT addresses are at most 16 bits wide
+        let methodaddr = methodaddr as u16;
 
-            if op.mnemonic == "ldc" {
-                let oldref = code_old[i + 1] as u16;
-                let newref = intrefs[&oldref];
-                code_new[i + 1] = newref as u8;
+        // map original bytecode offsets to program memory addresses
+        let mut pcaddrs: HashMap<u16, u16> = HashMap::new();
+        for (instr, addr) in instrs.iter().zip(&addrs) {
+            if let Some(pc) = instr.pc {
+                pcaddrs.insert(pc, methodaddr + addr);
             }
+        }
+        pcaddrs.insert(
+            codeblock.code.len() as u16,
+            methodaddr + code_new.len() as u16,
+        );
 
-            if methodname == MAIN_SIG && op.mnemonic == "return" {
-                code_new[i] = 0xFF_u8; // NOP
+        let locals: Vec<LocalEntry> = codeblock
+            .locals
+            .iter()
+            .filter_map(|var| {
+                Some(LocalEntry {
+                    index: var.index,
+                    name: var.name.to_string(),
+                    descriptor: var.descriptor.to_string(),
+                    start: *pcaddrs.get(&var.start_pc)?,
+                    end: *pcaddrs.get(&(var.start_pc + var.length))?,
+                })
+            })
+            .collect();
+
+        // line numbers refer to the source file of the main class
+        let mut lines: Vec<LineEntry> = Vec::new();
+        let source = classes.get(method.class).map(sourcefile).transpose()?;
+        if source == Some(debug.source.clone()) {
+            for (instr, addr) in instrs.iter().zip(addrs) {
+                if let Some(line) = instr.line {
+                    if lines.last().map(|entry| entry.line) != Some(line) {
+                        lines.push(LineEntry {
+                            addr: methodaddr + addr,
+                            line,
+                        });
+                    }
+                }
             }
         }
 
-        mem.append(&mut code_new);
+        debug.methods.push(MethodDebugInfo {
+            name: method.name,
+            addr: methodaddr,
+            size: code_new.len() as u16,
+            lines,
+            locals,
+        });
+
+        code.append(&mut code_new);
+    }
+
+    for value in program.consts {
+        methodlut.extend_from_slice(&value.to_be_bytes());
+    }
+
+    let mut mem = methodlut;
+    mem.append(&mut code);
+
+    if mem.len() > options.target.memory.program {
+        return Err(Error::other(format!(
+            "binary of {} bytes exceeds the program memory of {} bytes of target {}",
+            mem.len(),
+            options.target.memory.program,
+            options.target.name
+        )));
     }
 
-    mem
+    Ok((mem, debug))
 }
@@ -3,13 +3,137 @@ use std::collections::HashMap;
 use csv::ReaderBuilder;
 use serde::Deserialize;
 
+/// Kind of the bytecode operands of an instruction.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operand {
+    None,
+    /// Index into the local variable array.
+    Local,
+    /// Index into the local variable array, followed by a signed immediate (`iinc`).
+    LocalImmediate,
+    /// Signed immediate value.
+    Immediate,
+    /// Index of a constant in the constant pool.
+    Const,
+    /// Signed branch offset relative to the instruction address.
+    Branch,
+    /// Constant pool index of a field reference.
+    Field,
+    /// Constant pool index of a method reference.
+    Method,
+    /// Constant pool index of a class reference.
+    Class,
+    /// Constant pool index of an array class reference, followed by the number of dimensions (`multianewarray`).
+    ClassDimensions,
+    /// Element type of a primitive array (`newarray`).
+    ArrayType,
+    /// Padded jump table of branch offsets (`tableswitch`).
+    TableSwitch,
+    /// Padded list of match and branch offset pairs (`lookupswitch`).
+    LookupSwitch,
+    /// Instruction with widened operands (`wide`).
+    Wide,
+}
+
+/// Effect of an instruction on the control flow.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Flow {
+    /// Execution continues with the next instruction.
+    Next,
+    /// Conditional branch, execution continues with the branch target or the next instruction.
+    Branch,
+    /// Unconditional branch.
+    Goto,
+    /// Method invocation, execution continues with the next instruction after the invoked method returns.
+    Call,
+    /// Return from the method.
+    Return,
+    /// Branch to one of several targets selected by the value on top of the stack.
+    Switch,
+    /// Exception thrown.
+    Throw,
+}
+
 #[derive(Deserialize)]
-/// Associates a bytecode value to its corresponding mnemonic and its number of bytecode arguments.
+/// Associates a bytecode value to its corresponding mnemonic, its bytecode arguments and its effects.
 pub struct Op {
     #[serde(with = "hex")]
     pub opcode: [u8; 1],
     pub mnemonic: String,
     pub args: u8,
+    pub operand: Operand,
+    /// Number of stack slots popped, `None` if it depends on the operand (e.g. the descriptor of an invoked method).
+    pub pops: Option<u8>,
+    /// Number of stack slots pushed, `None` if it depends on the operand.
+    pub pushes: Option<u8>,
+    pub flow: Flow,
+    /// Index of the local variable accessed by the short forms of loads and stores, e.g. `iload_2`.
+    pub local: Option<u8>,
+    /// Whether the Bali processor executes the instruction, other instructions have to be lowered by `bake`.
+    /// Set according to the target profile by `Target::opmap`.
+    pub supported_on_bali: bool,
+}
+
+impl Op {
+    pub fn is_branch(&self) -> bool {
+        matches!(self.flow, Flow::Branch | Flow::Goto)
+    }
+
+    pub fn is_call(&self) -> bool {
+        self.flow == Flow::Call
+    }
+
+    ///
+    /// Determines whether the instruction ends a basic block without continuing with the next instruction.
+    ///
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self.flow,
+            Flow::Goto | Flow::Return | Flow::Switch | Flow::Throw
+        )
+    }
+
+    ///
+    /// Determines the number of operand bytes of the instruction at the given offset of the bytecode.
+    ///
+    /// The operands of `tableswitch`, `lookupswitch` and `wide` vary in length, so they are read from the bytecode.
+    /// Returns `None` if the bytecode ends within the operands.
+    ///
+    pub fn operand_len(&self, code: &[u8], pc: usize) -> Option<usize> {
+        let word = |offset: usize| -> Option<i32> {
+            let bytes = code.get(offset..offset + 4)?;
+            Some(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        // switch operands start at the next multiple of four
+        let padding = (4 - (pc + 1) % 4) % 4;
+        let base = pc + 1 + padding;
+
+        let len = match self.operand {
+            Operand::TableSwitch => {
+                let (low, high) = (word(base + 4)?, word(base + 8)?);
+                padding + 12 + 4 * usize::try_from(high as i64 - low as i64 + 1).ok()?
+            }
+            Operand::LookupSwitch => padding + 8 + 8 * usize::try_from(word(base + 4)?).ok()?,
+            // widened iinc has two operands of two bytes, other widened instructions a local index
+            Operand::Wide if *code.get(pc + 1)? == 0x84 => 5,
+            Operand::Wide => 3,
+            _ => self.args as usize,
+        };
+
+        (pc + 1 + len <= code.len()).then_some(len)
+    }
+
+    ///
+    /// Determines the index of the local variable a load, store or `iinc` instruction with the given operands accesses.
+    ///
+    pub fn local_index(&self, operands: &[u8]) -> Option<u16> {
+        match self.operand {
+            Operand::Local | Operand::LocalImmediate => operands.first().map(|index| *index as u16),
+            _ => self.local.map(|index| index as u16),
+        }
+    }
 }
 
 ///
@@ -0,0 +1,512 @@
+use crate::ir::{Instr, LutRef};
+use crate::opcodes::{opmap, Op};
+
+use std::collections::HashMap;
+
+const ICONST_0: u8 = 0x03;
+const IINC: u8 = 0x84;
+
+/// Savings of the peephole optimiser on a method.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Savings {
+    /// Bytes of code removed.
+    pub bytes: usize,
+    /// Estimated cycles saved, counting one cycle per instruction no longer executed
+    /// on a single pass through each rewritten sequence.
+    pub cycles: usize,
+}
+
+impl Savings {
+    pub fn is_empty(&self) -> bool {
+        self.bytes == 0 && self.cycles == 0
+    }
+}
+
+/// Rewrites a sequence of instructions, accumulating the cycles saved.
+type Rewrite = fn(&mut Vec<Instr>, &HashMap<u8, Op>) -> Option<usize>;
+
+/// Rewrites applied until none of them changes the instructions.
+const REWRITES: [Rewrite; 6] = [
+    remove_unreachable,
+    thread_jumps,
+    fold_constants,
+    fuse_iinc,
+    merge_iinc,
+    remove_dead_stores,
+];
+
+///
+/// Replaces `len` instructions starting at `start` with the given instructions.
+///
+/// Branches into the replaced instructions are redirected to the first replacing instruction,
+/// or the instruction following them if they are removed. Replacing instructions inherit the bytecode offset
+/// and source line of the first replaced instruction.
+///
+fn splice(instrs: &mut Vec<Instr>, start: usize, len: usize, mut with: Vec<Instr>) {
+    let (pc, line) = (instrs[start].pc, instrs[start].line);
+    if let Some(first) = with.first_mut() {
+        first.pc = first.pc.or(pc);
+    }
+    for instr in &mut with {
+        instr.line = instr.line.or(line);
+    }
+
+    let added = with.len();
+    instrs.splice(start..start + len, with);
+    for instr in instrs.iter_mut() {
+        instr.target = instr.target.map(|target| match target {
+            _ if target < start => target,
+            _ if target < start + len => start,
+            _ => target + added - len,
+        });
+    }
+}
+
+///
+/// Marks the instructions branched to.
+///
+fn branch_targets(instrs: &[Instr]) -> Vec<bool> {
+    let mut targets = vec![false; instrs.len()];
+    for target in instrs.iter().filter_map(|instr| instr.target) {
+        targets[target] = true;
+    }
+
+    targets
+}
+
+fn mnemonic<'a>(instrs: &[Instr], i: usize, opmap: &'a HashMap<u8, Op>) -> &'a str {
+    instrs
+        .get(i)
+        .map_or("", |instr| opmap[&instr.opcode].mnemonic.as_str())
+}
+
+///
+/// Returns the int pushed by an `iconst`, `bipush`, `sipush` or constant `ldc` instruction.
+///
+fn constant(instr: Option<&Instr>, opmap: &HashMap<u8, Op>) -> Option<i32> {
+    let instr = instr?;
+    match opmap[&instr.opcode].mnemonic.as_str() {
+        "iconst_m1" | "iconst_0" | "iconst_1" | "iconst_2" | "iconst_3" | "iconst_4"
+        | "iconst_5" => Some(instr.opcode as i32 - ICONST_0 as i32),
+        "bipush" => Some(instr.operands[0] as i8 as i32),
+        "sipush" => Some(i16::from_be_bytes([instr.operands[0], instr.operands[1]]) as i32),
+        "ldc" => match instr.lutref {
+            Some(LutRef::Constant(value)) => Some(value),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+///
+/// Evaluates a binary int instruction with JVM semantics, `None` for other instructions and divisions by zero.
+///
+fn evaluate(mnemonic: &str, a: i32, b: i32) -> Option<i32> {
+    match mnemonic {
+        "iadd" => Some(a.wrapping_add(b)),
+        "isub" => Some(a.wrapping_sub(b)),
+        "imul" => Some(a.wrapping_mul(b)),
+        "idiv" if b != 0 => Some(a.wrapping_div(b)),
+        "irem" if b != 0 => Some(a.wrapping_rem(b)),
+        "iand" => Some(a & b),
+        "ior" => Some(a | b),
+        "ixor" => Some(a ^ b),
+        // shift distances are masked to 5 bits, as by the JVM
+        "ishl" => Some(a.wrapping_shl(b as u32)),
+        "ishr" => Some(a.wrapping_shr(b as u32)),
+        "iushr" => Some((a as u32).wrapping_shr(b as u32) as i32),
+        _ => None,
+    }
+}
+
+fn iinc(index: u8, increment: i8) -> Instr {
+    Instr::new(IINC, vec![index, increment as u8])
+}
+
+///
+/// Removes instructions not reachable from the method entry, such as code following a `goto` or return
+/// that no branch leads to. Unreachable code is never executed, so no cycles are saved.
+///
+fn remove_unreachable(instrs: &mut Vec<Instr>, opmap: &HashMap<u8, Op>) -> Option<usize> {
+    let mut reached = vec![false; instrs.len()];
+    let mut worklist: Vec<usize> = vec![0];
+    while let Some(i) = worklist.pop() {
+        if i >= instrs.len() || reached[i] {
+            continue;
+        }
+        reached[i] = true;
+        worklist.extend(instrs[i].target);
+        if !opmap[&instrs[i].opcode].is_terminator() {
+            worklist.push(i + 1);
+        }
+    }
+    if reached.iter().all(|reached| *reached) {
+        return None;
+    }
+
+    let mut index: Vec<usize> = Vec::with_capacity(instrs.len());
+    let mut kept = 0;
+    for reached in &reached {
+        index.push(kept);
+        kept += *reached as usize;
+    }
+    let mut reached = reached.into_iter();
+    instrs.retain(|_| reached.next().unwrap());
+    for instr in instrs.iter_mut() {
+        instr.target = instr.target.map(|target| index[target]);
+    }
+
+    Some(0)
+}
+
+///
+/// Resolves the instruction each `goto` chain starting at an instruction ends at, along with the number of `goto`s
+/// passed, or `None` if the chain ends in a loop of `goto`s.
+///
+fn chain_ends(instrs: &[Instr], opmap: &HashMap<u8, Op>) -> Vec<Option<(usize, usize)>> {
+    let mut ends: Vec<Option<Option<(usize, usize)>>> = vec![None; instrs.len()];
+    for start in 0..instrs.len() {
+        let mut chain: Vec<usize> = Vec::new();
+        let mut i = start;
+        let mut end = loop {
+            if let Some(end) = ends[i] {
+                break end;
+            }
+            if chain.contains(&i) {
+                break None;
+            }
+            match instrs[i].target {
+                Some(target) if mnemonic(instrs, i, opmap) == "goto" => {
+                    chain.push(i);
+                    i = target;
+                }
+                _ => break Some((i, 0)),
+            }
+        };
+        ends[start] = ends[start].or(Some(end));
+        for i in chain.into_iter().rev() {
+            end = end.map(|(end, steps)| (end, steps + 1));
+            ends[i] = Some(end);
+        }
+    }
+
+    ends.into_iter().map(Option::flatten).collect()
+}
+
+///
+/// Redirects branches to a `goto` to the final target of the `goto` chain, saving a cycle per `goto` skipped,
+/// and removes a `goto` to the instruction following it. Branches into a loop of `goto`s are left unchanged.
+///
+fn thread_jumps(instrs: &mut Vec<Instr>, opmap: &HashMap<u8, Op>) -> Option<usize> {
+    let ends = chain_ends(instrs, opmap);
+    let mut cycles = 0;
+    for instr in instrs.iter_mut() {
+        if let Some((end, steps)) = instr.target.and_then(|target| ends[target]) {
+            if steps > 0 {
+                instr.target = Some(end);
+                cycles += steps;
+            }
+        }
+    }
+    if cycles > 0 {
+        return Some(cycles);
+    }
+
+    let i = (0..instrs.len())
+        .find(|i| mnemonic(instrs, *i, opmap) == "goto" && instrs[*i].target == Some(i + 1))?;
+    splice(instrs, i, 1, Vec::new());
+    Some(1)
+}
+
+///
+/// Replaces pushes of int constants followed by an arithmetic instruction with a push of the result.
+///
+fn fold_constants(instrs: &mut Vec<Instr>, opmap: &HashMap<u8, Op>) -> Option<usize> {
+    let targets = branch_targets(instrs);
+    for i in 0..instrs.len() {
+        let Some(a) = constant(instrs.get(i), opmap) else {
+            continue;
+        };
+        if mnemonic(instrs, i + 1, opmap) == "ineg" && !targets[i + 1] {
+            splice(instrs, i, 2, vec![Instr::push(a.wrapping_neg())]);
+            return Some(1);
+        }
+        let Some(b) = constant(instrs.get(i + 1), opmap) else {
+            continue;
+        };
+        if targets[i + 1] || targets.get(i + 2) != Some(&false) {
+            continue;
+        }
+        if let Some(value) = evaluate(mnemonic(instrs, i + 2, opmap), a, b) {
+            splice(instrs, i, 3, vec![Instr::push(value)]);
+            return Some(2);
+        }
+    }
+
+    None
+}
+
+///
+/// Replaces `iload n; <constant c>; iadd; istore n` and its `isub` variant with `iinc n c`
+/// if the increment fits into a byte.
+///
+fn fuse_iinc(instrs: &mut Vec<Instr>, opmap: &HashMap<u8, Op>) -> Option<usize> {
+    let targets = branch_targets(instrs);
+    for i in 0..instrs.len().saturating_sub(3) {
+        if !mnemonic(instrs, i, opmap).starts_with("iload")
+            || !mnemonic(instrs, i + 3, opmap).starts_with("istore")
+            || targets[i + 1..=i + 3].contains(&true)
+        {
+            continue;
+        }
+        let local = |j: usize| opmap[&instrs[j].opcode].local_index(&instrs[j].operands);
+        let (Some(index), Some(c)) = (local(i), constant(instrs.get(i + 1), opmap)) else {
+            continue;
+        };
+        let increment = match mnemonic(instrs, i + 2, opmap) {
+            "iadd" => Some(c),
+            "isub" => c.checked_neg(),
+            _ => None,
+        };
+        let (Some(increment), Ok(index)) = (increment, u8::try_from(index)) else {
+            continue;
+        };
+        if local(i + 3) != Some(index as u16) {
+            continue;
+        }
+        if let Ok(increment) = i8::try_from(increment) {
+            splice(instrs, i, 4, vec![iinc(index, increment)]);
+            return Some(3);
+        }
+    }
+
+    None
+}
+
+///
+/// Merges consecutive `iinc` instructions of the same local variable and removes `iinc` by zero.
+///
+fn merge_iinc(instrs: &mut Vec<Instr>, opmap: &HashMap<u8, Op>) -> Option<usize> {
+    let targets = branch_targets(instrs);
+    for i in 0..instrs.len() {
+        if mnemonic(instrs, i, opmap) != "iinc" {
+            continue;
+        }
+        let [index, a] = instrs[i].operands[..] else {
+            continue;
+        };
+        if a == 0 {
+            splice(instrs, i, 1, Vec::new());
+            return Some(1);
+        }
+        if mnemonic(instrs, i + 1, opmap) != "iinc" || targets[i + 1] {
+            continue;
+        }
+        let [next, b] = instrs[i + 1].operands[..] else {
+            continue;
+        };
+        if let (true, Some(sum)) = (next == index, (a as i8).checked_add(b as i8)) {
+            splice(instrs, i, 2, vec![iinc(index, sum)]);
+            return Some(1);
+        }
+    }
+
+    None
+}
+
+///
+/// Removes `istore n; iload n` pairs, leaving the stored value on the stack, if no other instruction
+/// reads local variable `n`.
+///
+fn remove_dead_stores(instrs: &mut Vec<Instr>, opmap: &HashMap<u8, Op>) -> Option<usize> {
+    let targets = branch_targets(instrs);
+    let local = |instr: &Instr| opmap[&instr.opcode].local_index(&instr.operands);
+    let reads = |instr: &Instr, index: u16| {
+        let mnemonic = opmap[&instr.opcode].mnemonic.as_str();
+        !mnemonic.contains("store") && local(instr) == Some(index)
+    };
+
+    for i in 0..instrs.len().saturating_sub(1) {
+        let (store, load) = (mnemonic(instrs, i, opmap), mnemonic(instrs, i + 1, opmap));
+        let pair = (store.starts_with("istore") && load.starts_with("iload"))
+            || (store.starts_with("astore") && load.starts_with("aload"));
+        if !pair || targets[i + 1] {
+            continue;
+        }
+        let Some(index) = local(&instrs[i]).filter(|index| local(&instrs[i + 1]) == Some(*index))
+        else {
+            continue;
+        };
+        let read_elsewhere = instrs
+            .iter()
+            .enumerate()
+            .any(|(j, instr)| j != i + 1 && reads(instr, index));
+        if !read_elsewhere {
+            splice(instrs, i, 2, Vec::new());
+            return Some(2);
+        }
+    }
+
+    None
+}
+
+///
+/// Applies peephole optimisations to the lowered instructions of a method: removal of unreachable code,
+/// jump threading, constant folding, `iinc` fusion and removal of dead `istore n; iload n` pairs.
+///
+/// Returns the optimised instructions along with the bytes and estimated cycles saved.
+///
+pub fn optimize(mut instrs: Vec<Instr>) -> (Vec<Instr>, Savings) {
+    let opmap = opmap();
+    let size = |instrs: &[Instr]| instrs.iter().map(|instr| instr.size()).sum::<usize>();
+    let before = size(&instrs);
+    let mut savings = Savings::default();
+
+    if instrs.is_empty() {
+        return (instrs, savings);
+    }
+    while let Some(cycles) = REWRITES
+        .iter()
+        .find_map(|rewrite| rewrite(&mut instrs, &opmap))
+    {
+        savings.cycles += cycles;
+    }
+    savings.bytes = before.saturating_sub(size(&instrs));
+
+    (instrs, savings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{decode, encode};
+
+    ///
+    /// Applies a single rewrite to the given bytecode, returning the cycles saved and the rewritten bytecode.
+    ///
+    fn rewrite(rewrite: Rewrite, code: &[u8]) -> (Option<usize>, Vec<u8>) {
+        let mut instrs = decode(code, &[]).unwrap();
+        let cycles = rewrite(&mut instrs, &opmap());
+        (cycles, encode(&instrs).unwrap().0)
+    }
+
+    #[test]
+    fn unreachable_code() {
+        // iconst_0; ireturn; iconst_1; ireturn
+        let code = [0x03, 0xac, 0x04, 0xac];
+        assert_eq!(
+            rewrite(remove_unreachable, &code),
+            (Some(0), vec![0x03, 0xac])
+        );
+        // goto +4; pop; return
+        let code = [0xa7, 0x00, 0x04, 0x57, 0xb1];
+        let expected = vec![0xa7, 0x00, 0x03, 0xb1];
+        assert_eq!(rewrite(remove_unreachable, &code), (Some(0), expected));
+        assert_eq!(rewrite(remove_unreachable, &[0x03, 0xac]).0, None);
+    }
+
+    #[test]
+    fn jump_threading() {
+        // iconst_0; ifeq +4; return; goto +3; iconst_1; ireturn
+        let code = [0x03, 0x99, 0x00, 0x04, 0xb1, 0xa7, 0x00, 0x03, 0x04, 0xac];
+        let expected = vec![0x03, 0x99, 0x00, 0x07, 0xb1, 0xa7, 0x00, 0x03, 0x04, 0xac];
+        assert_eq!(rewrite(thread_jumps, &code), (Some(1), expected));
+        // goto +3; iconst_1; ireturn
+        let code = [0xa7, 0x00, 0x03, 0x04, 0xac];
+        assert_eq!(rewrite(thread_jumps, &code), (Some(1), vec![0x04, 0xac]));
+        // goto +0
+        assert_eq!(rewrite(thread_jumps, &[0xa7, 0x00, 0x00]).0, None);
+        // iconst_0; ifeq +4; return; goto +4; return; goto -4, branching into a loop of two gotos
+        let code = [
+            0x03, 0x99, 0x00, 0x04, 0xb1, 0xa7, 0x00, 0x04, 0xb1, 0xa7, 0xff, 0xfc,
+        ];
+        assert_eq!(rewrite(thread_jumps, &code).0, None);
+        // iconst_0; ifeq +6; goto +3; goto +3; goto -6, branching into a loop of three gotos
+        let code = [
+            0x03, 0x99, 0x00, 0x06, 0xa7, 0x00, 0x03, 0xa7, 0x00, 0x03, 0xa7, 0xff, 0xfa,
+        ];
+        assert_eq!(rewrite(thread_jumps, &code).0, Some(1));
+        let (instrs, _) = optimize(decode(&code, &[]).unwrap());
+        assert!(encode(&instrs).is_ok());
+    }
+
+    #[test]
+    fn constant_folding() {
+        // iconst_2; iconst_3; iadd; ireturn
+        let code = [0x05, 0x06, 0x60, 0xac];
+        assert_eq!(rewrite(fold_constants, &code), (Some(2), vec![0x08, 0xac]));
+        // bipush 7; ineg; ireturn
+        let code = [0x10, 0x07, 0x74, 0xac];
+        let expected = vec![0x10, 0xf9, 0xac];
+        assert_eq!(rewrite(fold_constants, &code), (Some(1), expected));
+        // iconst_1; iconst_0; idiv; ireturn
+        assert_eq!(rewrite(fold_constants, &[0x04, 0x03, 0x6c, 0xac]).0, None);
+        // iconst_2; iconst_3; goto -1 (into the iconst_3); iadd
+        let code = [0x05, 0x06, 0xa7, 0xff, 0xff, 0x60];
+        assert_eq!(rewrite(fold_constants, &code).0, None);
+    }
+
+    #[test]
+    fn iinc_fusion() {
+        // iload_0; iconst_1; iadd; istore_0; return
+        let code = [0x1a, 0x04, 0x60, 0x3b, 0xb1];
+        let expected = vec![0x84, 0x00, 0x01, 0xb1];
+        assert_eq!(rewrite(fuse_iinc, &code), (Some(3), expected));
+        // iload_1; bipush 5; isub; istore_1; return
+        let code = [0x1b, 0x10, 0x05, 0x64, 0x3c, 0xb1];
+        let expected = vec![0x84, 0x01, 0xfb, 0xb1];
+        assert_eq!(rewrite(fuse_iinc, &code), (Some(3), expected));
+        // iload_0; iconst_1; iadd; istore_1; return
+        assert_eq!(rewrite(fuse_iinc, &[0x1a, 0x04, 0x60, 0x3c, 0xb1]).0, None);
+        // iload_0; sipush 256; iadd; istore_0; return
+        let code = [0x1a, 0x11, 0x01, 0x00, 0x60, 0x3b, 0xb1];
+        assert_eq!(rewrite(fuse_iinc, &code).0, None);
+    }
+
+    #[test]
+    fn iinc_merging() {
+        // iinc 0 2; iinc 0 3; return
+        let code = [0x84, 0x00, 0x02, 0x84, 0x00, 0x03, 0xb1];
+        let expected = vec![0x84, 0x00, 0x05, 0xb1];
+        assert_eq!(rewrite(merge_iinc, &code), (Some(1), expected));
+        // iinc 0 0; return
+        let code = [0x84, 0x00, 0x00, 0xb1];
+        assert_eq!(rewrite(merge_iinc, &code), (Some(1), vec![0xb1]));
+        // iinc 0 1; iinc 1 1; return
+        let code = [0x84, 0x00, 0x01, 0x84, 0x01, 0x01, 0xb1];
+        assert_eq!(rewrite(merge_iinc, &code).0, None);
+        // iinc 0 100; iinc 0 100; return
+        let code = [0x84, 0x00, 0x64, 0x84, 0x00, 0x64, 0xb1];
+        assert_eq!(rewrite(merge_iinc, &code).0, None);
+    }
+
+    #[test]
+    fn dead_stores() {
+        // iconst_1; istore_0; iload_0; ireturn
+        let code = [0x04, 0x3b, 0x1a, 0xac];
+        assert_eq!(
+            rewrite(remove_dead_stores, &code),
+            (Some(2), vec![0x04, 0xac])
+        );
+        // iconst_1; istore_0; iload_0; iload_0; iadd; ireturn
+        let code = [0x04, 0x3b, 0x1a, 0x1a, 0x60, 0xac];
+        assert_eq!(rewrite(remove_dead_stores, &code).0, None);
+    }
+
+    #[test]
+    fn savings() {
+        // goto +3; iconst_2; iconst_3; iadd; ireturn
+        let instrs = decode(&[0xa7, 0x00, 0x03, 0x05, 0x06, 0x60, 0xac], &[]).unwrap();
+        let (instrs, savings) = optimize(instrs);
+
+        assert_eq!(encode(&instrs).unwrap().0, vec![0x08, 0xac]);
+        assert_eq!(
+            savings,
+            Savings {
+                bytes: 5,
+                cycles: 3
+            }
+        );
+        assert!(optimize(Vec::new()).1.is_empty());
+    }
+}
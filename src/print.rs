@@ -1,11 +1,23 @@
-use crate::MAIN_SIG;
 use crate::{methodrefs, opmap, parse_method_signature};
-use crate::{BaliCode, ClassFile, ConstPoolValue, Op};
+use crate::{split_signature, FieldType, LUTENTRY};
+use crate::{BaliCode, BasicBlock, ClassFile, ConstPoolValue, DebugInfo, LutFormat, Op, Operand};
+use crate::{EdgeKind, GraphFormat, LoweredMethod};
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Error;
 
 pub fn constoutput(classinfo: &ClassFile, index: &u16, value: &ConstPoolValue) -> String {
     match value {
         ConstPoolValue::Class(name_ref) => format!("{}: {}", index, name_ref),
         ConstPoolValue::Integer(int_const) => format!("{}: {}", index, int_const),
+        ConstPoolValue::Float(float_const) => format!("{}: {}", index, float_const),
+        ConstPoolValue::Long(long_const) => format!("{}: {}", index, long_const),
+        ConstPoolValue::FieldRef(class_ref, desc_ref) => format!(
+            "{}: {}, {}",
+            index,
+            class_ref,
+            parse_method_signature(classinfo, desc_ref).unwrap()
+        ),
         ConstPoolValue::MethodRef(_, desc_ref) => format!(
             "{}: {}",
             index,
@@ -18,72 +30,107 @@ pub fn constoutput(classinfo: &ClassFile, index: &u16, value: &ConstPoolValue) -
     }
 }
 
-fn fieldtype(typeid: &str) -> String {
-    return match typeid {
-        "B" => "byte".to_string(),
-        "C" => "char".to_string(),
-        "I" => "int".to_string(),
-        "S" => "short".to_string(),
-        "V" => "void".to_string(),
-        "Z" => "boolean".to_string(),
-        "[B" => "byte[]".to_string(),
-        "[C" => "char[]".to_string(),
-        "[I" => "int[]".to_string(),
-        "[S" => "short[]".to_string(),
-        "[Z" => "boolean[]".to_string(),
-        &_ => panic!("Invalid field type identifier pattern"),
+pub fn methodstring(sig: &str) -> String {
+    let (name, descriptor) = split_signature(sig).unwrap();
+
+    let returntype = match descriptor.ret {
+        Some(fieldtype) => fieldtype.to_string(),
+        None => "void".to_string(),
     };
+    let arglist: Vec<String> = descriptor.params.iter().map(|p| p.to_string()).collect();
+
+    format!("{} {}({})", returntype, name, arglist.join(", "))
 }
 
-pub fn methodstring(sig: &str) -> String {
-    if sig == MAIN_SIG {
-        return "void main(String[])".to_string();
+///
+/// Reads the 16-bit or 32-bit (`goto_w`, `jsr_w`) offset of a branch instruction.
+///
+fn branch_offset(args: &[u8]) -> i32 {
+    match *args {
+        [a, b, c, d] => i32::from_be_bytes([a, b, c, d]),
+        [a, b] => i16::from_be_bytes([a, b]) as i32,
+        _ => 0,
     }
+}
 
-    let signature = sig.to_string();
-    let split: Vec<String> = signature
-        .split(|c| (c == '(') || (c == ')'))
-        .map(|s| s.to_string())
-        .collect();
+fn sourceline(source: &[String], line: u16) -> String {
+    match source.get((line as usize).wrapping_sub(1)) {
+        Some(text) => format!("     ; {:>4}: {}\n", line, text.trim()),
+        None => format!("     ; line {}\n", line),
+    }
+}
 
-    let name = &split[0];
-    let returntype = fieldtype(&split[2]);
+///
+/// Disassembles the instruction at `addr` of the method bytecode, returning its text and the address of the next instruction.
+///
+/// Invoked methods are named by `methodname`, accessed local variables are named if the code contains a local variable table.
+///
+fn instrtext(
+    code_info: &BaliCode,
+    addr: u16,
+    methodname: &dyn Fn(u16) -> Option<String>,
+) -> (String, u16) {
+    let opmap = opmap();
+    let code = &code_info.code;
+    let opcode = code[addr as usize];
 
-    let mut arglist: Vec<String> = Vec::new();
-    let mut is_array = false;
-    for c in split[1].chars() {
-        match c {
-            '[' => {
-                is_array = true;
-            }
-            _ => {
-                let expr = if is_array {
-                    format!("[{}", c)
-                } else {
-                    c.to_string()
-                };
-                arglist.push(fieldtype(&expr));
-                is_array = false;
-            }
+    let op: &Op = match opmap.get(&opcode) {
+        Some(op) => op,
+        None => return (format!("{:>3x}: db {:#04x}", addr, opcode), addr + 1),
+    };
+    let len = op
+        .operand_len(code, addr as usize)
+        .unwrap_or(code.len() - addr as usize - 1);
+    let args = &code[addr as usize + 1..addr as usize + 1 + len];
+
+    let arg: u16 = match args[..] {
+        [a] => a as u16,
+        [a, b, ..] => (a as u16) << 8 | (b as u16),
+        _ => 0,
+    };
+    let mut text = match (op.operand, methodname(arg)) {
+        (Operand::None, _) => format!("{:>3x}: {:15}", addr, op.mnemonic),
+        // print signature of invoked method
+        (Operand::Method, Some(method)) => {
+            format!("{:>3x}: {:15} {}", addr, op.mnemonic, method)
         }
-    }
+        // print absolute address of branch target
+        (Operand::Branch, _) => format!(
+            "{:>3x}: {:15} -> {:x}",
+            addr,
+            op.mnemonic,
+            addr as i32 + branch_offset(args)
+        ),
+        (Operand::ClassDimensions, _) => {
+            format!("{:>3x}: {:15} {:#06x} {}", addr, op.mnemonic, arg, args[2])
+        }
+        _ if args.len() == 1 => format!("{:>3x}: {:15} {:#04x}", addr, op.mnemonic, arg),
+        _ if args.len() == 2 => format!("{:>3x}: {:15} {:#06x}", addr, op.mnemonic, arg),
+        _ => {
+            let hexbytes: Vec<String> = args.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{:>3x}: {:15} {}", addr, op.mnemonic, hexbytes.join(" "))
+        }
+    };
 
-    let mut argstring = String::new();
-    arglist.iter().fold(true, |first, elem| {
-        if !first {
-            argstring.push_str(", ");
+    let nextaddr = addr + len as u16 + 1;
+
+    // name local variables accessed by the instruction
+    if let Some(index) = op.local_index(args) {
+        if let Some(var) = code_info.local(index, addr, nextaddr) {
+            let vartype = var
+                .descriptor
+                .parse::<FieldType>()
+                .map_or(var.descriptor.to_string(), |t| t.to_string());
+            text = format!("{:30} ; {}: {}", text, var.name, vartype);
         }
-        argstring.push_str(elem);
-        false
-    });
+    }
 
-    format!("{} {}({})", returntype, name, argstring)
+    (text, nextaddr)
 }
 
-pub fn print_method(classinfo: &ClassFile, name: &str, code_info: &BaliCode) {
-    let opmap = opmap();
-    let mut code_iter = code_info.code.iter();
+pub fn print_method(classinfo: &ClassFile, name: &str, code_info: &BaliCode, source: &[String]) {
     let methodrefs = methodrefs(classinfo);
+    let methodname = |index: u16| methodrefs.get_by_left(&index).cloned();
 
     let mut output: Vec<u8> = Vec::new();
 
@@ -94,45 +141,137 @@ pub fn print_method(classinfo: &ClassFile, name: &str, code_info: &BaliCode) {
         &mut format!("local variable array size: {:>3}\n\n", code_info.max_locals).into_bytes(),
     );
 
-    let mut addr = 0;
-    while let Some(opcode) = code_iter.next() {
-        let op: &Op = &opmap[opcode];
-
-        match op.args {
-            0 => output.append(&mut format!("{:>3x}: {:15}\n", addr, op.mnemonic).into_bytes()),
-            1 => {
-                let arg = code_iter.next().unwrap();
-                output.append(
-                    &mut format!("{:>3x}: {:15} {:#04x}\n", addr, op.mnemonic, arg).into_bytes(),
-                );
+    let mut addr: u16 = 0;
+    while (addr as usize) < code_info.code.len() {
+        // interleave source lines starting at this instruction
+        for (_, line) in code_info.lines.iter().filter(|(pc, _)| *pc == addr) {
+            output.append(&mut sourceline(source, *line).into_bytes());
+        }
+
+        let (text, nextaddr) = instrtext(code_info, addr, &methodname);
+        output.append(&mut format!("{}\n", text).into_bytes());
+        addr = nextaddr;
+    }
+
+    let inputstruct =
+        bat::Input::from_reader(std::io::Cursor::new(&output)).title(name.to_string());
+
+    bat::PrettyPrinter::new()
+        .input(inputstruct)
+        .header(true)
+        .grid(true)
+        .line_numbers(true)
+        .print()
+        .unwrap();
+}
+
+///
+/// Prints the disassembly of a Bali binary.
+///
+/// If debug information is available, method boundaries and source lines are annotated,
+/// otherwise the LUT entries are printed as raw values. Method LUT entries are read in the given format.
+/// Returns an error if the binary is too short to hold the LUT its first entry describes.
+///
+pub fn print_disasm(
+    name: &str,
+    bin: &[u8],
+    lut: &LutFormat,
+    debug: Option<&DebugInfo>,
+    source: &[String],
+) -> Result<(), Error> {
+    let opmap = opmap();
+    let mut output: Vec<u8> = Vec::new();
+
+    // the main method follows the LUT
+    if bin.len() < LUTENTRY {
+        return Err(Error::other(format!(
+            "binary of {} bytes is shorter than a LUT entry",
+            bin.len()
+        )));
+    }
+    let (lutsize, _, _) = lut.unpack(&bin[..LUTENTRY]);
+    if lutsize > bin.len() || lutsize % LUTENTRY != 0 {
+        return Err(Error::other(format!(
+            "main method address {:#06x} is not a LUT entry boundary within the binary of {} bytes",
+            lutsize,
+            bin.len()
+        )));
+    }
+    let methodcount = debug.map_or(0, |d| d.methods.len());
+
+    for (index, entry) in bin[..lutsize].chunks(LUTENTRY).enumerate() {
+        let line = match debug.and_then(|d| d.methods.get(index)) {
+            Some(method) => {
+                let (_, args, locals) = lut.unpack(entry);
+                format!(
+                    "lut {:>3}: method {:#06x} args {:>3} locals {:>3}  {}\n",
+                    index, method.addr, args, locals, method.name
+                )
             }
-            2 => {
-                let arg1 = code_iter.next().unwrap();
-                let arg2 = code_iter.next().unwrap();
-                let arg: u16 = (*arg1 as u16) << 8 | (*arg2 as u16);
-
-                // if static method invocation, print signature of invoked method
-                if op.mnemonic == "invokestatic" {
-                    output.append(
-                        &mut format!(
-                            "{:>3x}: {:15} {}\n",
-                            addr,
-                            op.mnemonic,
-                            methodrefs.get_by_left(&arg).unwrap()
-                        )
-                        .into_bytes(),
-                    );
-                } else {
-                    output.append(
-                        &mut format!("{:>3x}: {:15} {:#06x}\n", addr, op.mnemonic, arg)
-                            .into_bytes(),
-                    );
-                }
+            None if index >= methodcount && debug.is_some() => format!(
+                "lut {:>3}: const  {}\n",
+                index,
+                i32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]])
+            ),
+            None => format!(
+                "lut {:>3}: {:02x} {:02x} {:02x} {:02x}\n",
+                index, entry[0], entry[1], entry[2], entry[3]
+            ),
+        };
+        output.append(&mut line.into_bytes());
+    }
+
+    let mut addr = lutsize;
+    let mut lastline = None;
+    while addr < bin.len() {
+        if let Some(method) = debug.and_then(|d| d.methods.iter().find(|m| m.addr as usize == addr))
+        {
+            output.append(&mut format!("\n{}:\n", method.name).into_bytes());
+            lastline = None;
+        }
+
+        let line = debug.and_then(|d| d.line(addr as u16));
+        if let Some(line) = line {
+            if lastline != Some(line) {
+                output.append(&mut sourceline(source, line).into_bytes());
             }
-            _ => panic!("unknown opcode"),
+        }
+        lastline = line;
+
+        let opcode = bin[addr];
+        let (mnemonic, args) = match opmap.get(&opcode) {
+            Some(op) => (
+                op.mnemonic.to_string(),
+                op.operand_len(bin, addr).unwrap_or(bin.len() - addr - 1),
+            ),
+            None => (format!("db {:#04x}", opcode), 0),
         };
+        let operands = &bin[addr + 1..(addr + 1 + args).min(bin.len())];
+        let hexbytes: Vec<String> = operands.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut text = format!("{:>4x}: {:15} {}", addr, mnemonic, hexbytes.join(" "));
+
+        // name local variables accessed by the instruction
+        let index = opmap.get(&opcode).and_then(|op| op.local_index(operands));
+        let method = debug.and_then(|d| d.method(addr as u16));
+        if let (Some(index), Some(method)) = (index, method) {
+            let nextaddr = (addr + 1 + args) as u16;
+            if let Some(var) = method
+                .locals
+                .iter()
+                .find(|v| v.index == index && v.start <= nextaddr && (addr as u16) < v.end)
+            {
+                text = format!("{:30} ; LVA[{}] = {}", text, index, var.name);
+            }
+        }
 
-        addr += op.args + 1;
+        // annotate absolute address of branch target
+        if opmap.get(&opcode).map(|op| op.operand) == Some(Operand::Branch) {
+            let target = addr as i32 + branch_offset(operands);
+            text = format!("{:30} ; -> {:x}", text, target);
+        }
+
+        output.append(&mut format!("{}\n", text).into_bytes());
+        addr += 1 + args;
     }
 
     let inputstruct =
@@ -145,4 +284,242 @@ pub fn print_method(classinfo: &ClassFile, name: &str, code_info: &BaliCode) {
         .line_numbers(true)
         .print()
         .unwrap();
+
+    Ok(())
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn mermaid_escape(text: &str) -> String {
+    text.replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+}
+
+///
+/// Renders the control flow graph of a method as Graphviz DOT or Mermaid flowchart.
+///
+/// Each node lists the disassembled instructions of a basic block, edges are labeled with the branch outcome
+/// or switch case they are taken for. Invoked methods are named by `methodname`.
+///
+pub fn cfgoutput(
+    name: &str,
+    code_info: &BaliCode,
+    blocks: &[BasicBlock],
+    format: GraphFormat,
+    methodname: &dyn Fn(u16) -> Option<String>,
+) -> String {
+    let mut output = match format {
+        GraphFormat::Dot => format!(
+            "digraph \"{}\" {{\n    node [shape=box, fontname=\"monospace\"];\n",
+            dot_escape(name)
+        ),
+        GraphFormat::Mermaid => format!(
+            "---\ntitle: \"{}\"\n---\nflowchart TD\n",
+            mermaid_escape(name)
+        ),
+    };
+
+    for block in blocks {
+        let mut lines: Vec<String> = Vec::new();
+        let mut addr = block.start as u16;
+        while (addr as usize) < block.end {
+            let (text, nextaddr) = instrtext(code_info, addr, methodname);
+            lines.push(text.trim_end().to_string());
+            addr = nextaddr;
+        }
+
+        output.push_str(&match format {
+            GraphFormat::Dot => {
+                let label: String = lines
+                    .iter()
+                    .map(|l| format!("{}\\l", dot_escape(l)))
+                    .collect();
+                format!("    b{:x} [label=\"{}\"];\n", block.start, label)
+            }
+            GraphFormat::Mermaid => {
+                let label: Vec<String> = lines.iter().map(|l| mermaid_escape(l)).collect();
+                format!("    b{:x}[\"{}\"]\n", block.start, label.join("<br/>"))
+            }
+        });
+    }
+
+    for block in blocks {
+        for edge in &block.successors {
+            // fall-through edges are only labeled if the block may also branch
+            let label = match edge.kind {
+                EdgeKind::Goto => None,
+                EdgeKind::Next if block.successors.len() == 1 => None,
+                kind => Some(kind.to_string()),
+            };
+            output.push_str(&match (format, label) {
+                (GraphFormat::Dot, None) => {
+                    format!("    b{:x} -> b{:x};\n", block.start, edge.target)
+                }
+                (GraphFormat::Dot, Some(label)) => format!(
+                    "    b{:x} -> b{:x} [label=\"{}\"];\n",
+                    block.start, edge.target, label
+                ),
+                (GraphFormat::Mermaid, None) => {
+                    format!("    b{:x} --> b{:x}\n", block.start, edge.target)
+                }
+                (GraphFormat::Mermaid, Some(label)) => format!(
+                    "    b{:x} -->|\"{}\"| b{:x}\n",
+                    block.start, label, edge.target
+                ),
+            });
+        }
+    }
+
+    if format == GraphFormat::Dot {
+        output.push_str("}\n");
+    }
+
+    output
+}
+
+///
+/// Renders the call graph of the lowered methods as Graphviz DOT or Mermaid flowchart.
+///
+/// Methods missing from `reached` are left out of the binary and drawn dashed.
+///
+pub fn callgraphoutput(
+    name: &str,
+    methods: &[LoweredMethod],
+    reached: &BTreeSet<String>,
+    format: GraphFormat,
+) -> String {
+    let mut output = match format {
+        GraphFormat::Dot => format!(
+            "digraph \"{}\" {{\n    node [shape=box, fontname=\"monospace\"];\n",
+            dot_escape(name)
+        ),
+        GraphFormat::Mermaid => format!(
+            "---\ntitle: \"{}\"\n---\nflowchart LR\n    classDef eliminated stroke-dasharray: 5 5, color: #888\n",
+            mermaid_escape(name)
+        ),
+    };
+
+    let ids: HashMap<&str, usize> = methods
+        .iter()
+        .enumerate()
+        .map(|(i, method)| (method.name.as_str(), i))
+        .collect();
+
+    for (i, method) in methods.iter().enumerate() {
+        let eliminated = !reached.contains(&method.name);
+        output.push_str(&match (format, eliminated) {
+            (GraphFormat::Dot, false) => {
+                format!("    m{} [label=\"{}\"];\n", i, dot_escape(&method.name))
+            }
+            (GraphFormat::Dot, true) => format!(
+                "    m{} [label=\"{}\", style=dashed, color=gray50, fontcolor=gray50];\n",
+                i,
+                dot_escape(&method.name)
+            ),
+            (GraphFormat::Mermaid, false) => {
+                format!("    m{}[\"{}\"]\n", i, mermaid_escape(&method.name))
+            }
+            (GraphFormat::Mermaid, true) => format!(
+                "    m{}[\"{}\"]:::eliminated\n",
+                i,
+                mermaid_escape(&method.name)
+            ),
+        });
+    }
+
+    for (i, method) in methods.iter().enumerate() {
+        for callee in method.callees.iter().filter_map(|c| ids.get(c.as_str())) {
+            output.push_str(&match format {
+                GraphFormat::Dot => format!("    m{} -> m{};\n", i, callee),
+                GraphFormat::Mermaid => format!("    m{} --> m{}\n", i, callee),
+            });
+        }
+    }
+
+    if format == GraphFormat::Dot {
+        output.push_str("}\n");
+    }
+
+    output
+}
+
+///
+/// Renders the bytes and estimated cycles saved by the peephole optimiser per method, followed by the total.
+///
+pub fn savingsoutput(methods: &[LoweredMethod]) -> String {
+    let mut output = String::new();
+    let (mut bytes, mut cycles) = (0, 0);
+
+    for method in methods.iter().filter(|method| !method.savings.is_empty()) {
+        output.push_str(&format!(
+            "{}: {} bytes, ~{} cycles saved\n",
+            method.name, method.savings.bytes, method.savings.cycles
+        ));
+        bytes += method.savings.bytes;
+        cycles += method.savings.cycles;
+    }
+    output.push_str(&format!(
+        "total: {} bytes, ~{} cycles saved\n",
+        bytes, cycles
+    ));
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_blocks;
+
+    ///
+    /// Renders the control flow graph of a counting loop, with the instructions of each block on separate lines.
+    ///
+    fn loop_graph(format: GraphFormat) -> String {
+        // iconst_3; istore_0; loop: iinc 0 -1; iload_0; ifne loop; iload_0; ireturn
+        let code = vec![
+            0x06, 0x3b, 0x84, 0x00, 0xff, 0x1a, 0x9a, 0xff, 0xfc, 0x1a, 0xac,
+        ];
+        let blocks = basic_blocks(&code).unwrap();
+        let code_info = BaliCode {
+            access_flags: 0,
+            max_stack: 1,
+            max_locals: 1,
+            argcount: 0,
+            code,
+            lines: Vec::new(),
+            locals: Vec::new(),
+        };
+        cfgoutput("loop()I", &code_info, &blocks, format, &|_| None)
+    }
+
+    #[test]
+    fn cfg_dot() {
+        let output = loop_graph(GraphFormat::Dot);
+        let nodes = output.lines().filter(|l| l.contains("[label=\"  ")).count();
+
+        assert!(output.starts_with("digraph \"loop()I\" {\n"));
+        assert!(output.ends_with("}\n"));
+        assert_eq!(nodes, 3);
+        assert!(output.contains("    b2 [label=\"  2: iinc"));
+        assert!(output.contains("  6: ifne            -> 2\\l\"];\n"));
+        assert!(output.contains("    b0 -> b2;\n"));
+        assert!(output.contains("    b2 -> b2 [label=\"taken\"];\n"));
+        assert!(output.contains("    b2 -> b9 [label=\"next\"];\n"));
+        assert!(!output.contains("    b9 ->"));
+    }
+
+    #[test]
+    fn cfg_mermaid() {
+        let output = loop_graph(GraphFormat::Mermaid);
+
+        assert!(output.starts_with("---\ntitle: \"loop()I\"\n---\nflowchart TD\n"));
+        assert!(output.contains("    b9[\"  9: iload_0<br/>  a: ireturn\"]\n"));
+        assert!(output.contains("    b0 --> b2\n"));
+        assert!(output.contains("    b2 -->|\"taken\"| b2\n"));
+        assert!(output.contains("    b2 -->|\"next\"| b9\n"));
+        assert!(!output.contains("    b9 -->"));
+    }
 }
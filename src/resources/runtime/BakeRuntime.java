@@ -0,0 +1,460 @@
+/**
+ * Runtime helper methods, linked into Bali binaries when the lowering of an instruction requires them.
+ *
+ * The helpers may only use instructions supported by Bali, or instructions bake lowers without helpers.
+ * After modifying this file, recompile it with:
+ *
+ *     javac --release 8 -g:none -d src/resources/runtime src/resources/runtime/BakeRuntime.java
+ */
+public class BakeRuntime {
+
+    /*
+     * Long values are represented as pairs of ints, the high word followed by the low word.
+     * Helpers returning a long return its low word and pass its high word in the static field hi.
+     */
+    static int hi;
+
+    /** High word of the last long result. */
+    static int high() {
+        return hi;
+    }
+
+    /** Lowering of lreturn. */
+    static int lreturn(int h, int l) {
+        hi = h;
+        return l;
+    }
+
+    /** Unsigned comparison a < b of two ints. */
+    static boolean ult(int a, int b) {
+        return (a ^ 0x80000000) < (b ^ 0x80000000);
+    }
+
+    static int ladd(int ah, int al, int bh, int bl) {
+        int l = al + bl;
+        hi = ult(l, al) ? ah + bh + 1 : ah + bh;
+        return l;
+    }
+
+    static int lsub(int ah, int al, int bh, int bl) {
+        hi = ult(al, bl) ? ah - bh - 1 : ah - bh;
+        return al - bl;
+    }
+
+    static int lneg(int ah, int al) {
+        return lsub(0, 0, ah, al);
+    }
+
+    static int lmul(int ah, int al, int bh, int bl) {
+        // full 64-bit product of the low words from 16-bit partial products
+        int a0 = al & 0xffff;
+        int a1 = al >>> 16;
+        int b0 = bl & 0xffff;
+        int b1 = bl >>> 16;
+        int p00 = a0 * b0;
+        int p01 = a0 * b1;
+        int p10 = a1 * b0;
+        int mid = (p00 >>> 16) + (p01 & 0xffff) + (p10 & 0xffff);
+        hi = a1 * b1 + (p01 >>> 16) + (p10 >>> 16) + (mid >>> 16) + ah * bl + al * bh;
+        return (p00 & 0xffff) | (mid << 16);
+    }
+
+    static int lcmp(int ah, int al, int bh, int bl) {
+        if (ah != bh) {
+            return ah < bh ? -1 : 1;
+        }
+        if (al == bl) {
+            return 0;
+        }
+        return ult(al, bl) ? -1 : 1;
+    }
+
+    static int land(int ah, int al, int bh, int bl) {
+        hi = ah & bh;
+        return al & bl;
+    }
+
+    static int lor(int ah, int al, int bh, int bl) {
+        hi = ah | bh;
+        return al | bl;
+    }
+
+    static int lxor(int ah, int al, int bh, int bl) {
+        hi = ah ^ bh;
+        return al ^ bl;
+    }
+
+    static int lshl(int ah, int al, int s) {
+        s &= 63;
+        if (s == 0) {
+            hi = ah;
+            return al;
+        }
+        if (s >= 32) {
+            hi = al << (s - 32);
+            return 0;
+        }
+        hi = (ah << s) | (al >>> (32 - s));
+        return al << s;
+    }
+
+    static int lshr(int ah, int al, int s) {
+        s &= 63;
+        if (s == 0) {
+            hi = ah;
+            return al;
+        }
+        if (s >= 32) {
+            hi = ah >> 31;
+            return ah >> (s - 32);
+        }
+        hi = ah >> s;
+        return (al >>> s) | (ah << (32 - s));
+    }
+
+    static int lushr(int ah, int al, int s) {
+        s &= 63;
+        if (s == 0) {
+            hi = ah;
+            return al;
+        }
+        if (s >= 32) {
+            hi = 0;
+            return ah >>> (s - 32);
+        }
+        hi = ah >>> s;
+        return (al >>> s) | (ah << (32 - s));
+    }
+
+    /*
+     * Float values are represented by their IEEE-754 single-precision bit patterns.
+     * Results are rounded to nearest, ties to even, as on the JVM.
+     */
+    static boolean isnan(int x) {
+        return (x & 0x7fffffff) > 0x7f800000;
+    }
+
+    /** Shifts right, keeping a sticky bit if any one bits are shifted out. */
+    static int sticky(int m, int s) {
+        if (s >= 32) {
+            return m != 0 ? 1 : 0;
+        }
+        int out = m & ((1 << s) - 1);
+        return (m >>> s) | (out != 0 ? 1 : 0);
+    }
+
+    /**
+     * Rounds the value m / 2^26 * 2^(e - 127) to a float, where m has three bits below the float mantissa
+     * and carries the sign given as sign bit.
+     */
+    static int round(int sign, int e, int m) {
+        if (m == 0) {
+            return sign;
+        }
+        while ((m >>> 27) != 0) {
+            m = sticky(m, 1);
+            e++;
+        }
+        while ((m >>> 26) == 0) {
+            m <<= 1;
+            e--;
+        }
+        if (e >= 255) {
+            return sign | 0x7f800000;
+        }
+        if (e <= 0) {
+            m = sticky(m, 1 - e);
+            e = 1;
+        }
+        int low = m & 7;
+        m >>>= 3;
+        if (low > 4 || (low == 4 && (m & 1) != 0)) {
+            m++;
+        }
+        // a carry out of the mantissa increments the exponent, up to infinity
+        return sign | (((e - 1) << 23) + m);
+    }
+
+    static int fadd(int a, int b) {
+        if (isnan(a) || isnan(b)) {
+            return 0x7fc00000;
+        }
+        if ((a & 0x7f800000) == 0x7f800000) {
+            return (b & 0x7f800000) == 0x7f800000 && a != b ? 0x7fc00000 : a;
+        }
+        if ((b & 0x7f800000) == 0x7f800000) {
+            return b;
+        }
+        if ((a & 0x7fffffff) < (b & 0x7fffffff)) {
+            int t = a;
+            a = b;
+            b = t;
+        }
+        int ea = (a >>> 23) & 0xff;
+        int eb = (b >>> 23) & 0xff;
+        int ma = a & 0x7fffff;
+        int mb = b & 0x7fffff;
+        if (ea == 0) {
+            ea = 1;
+        } else {
+            ma |= 0x800000;
+        }
+        if (eb == 0) {
+            eb = 1;
+        } else {
+            mb |= 0x800000;
+        }
+        ma <<= 3;
+        mb = sticky(mb << 3, ea - eb);
+        if ((a ^ b) < 0) {
+            // the sum of x and -x is +0
+            return round(ma == mb ? 0 : a & 0x80000000, ea, ma - mb);
+        }
+        return round(a & 0x80000000, ea, ma + mb);
+    }
+
+    static int fsub(int a, int b) {
+        return fadd(a, b ^ 0x80000000);
+    }
+
+    static int fneg(int a) {
+        return a ^ 0x80000000;
+    }
+
+    /** Exponent of a float, adjusted for the normalization of denormal mantissas by mantissa(). */
+    static int exponent(int x) {
+        int e = (x >>> 23) & 0xff;
+        int m = x & 0x7fffff;
+        if (e == 0) {
+            e = 1;
+            while (m < 0x800000) {
+                m <<= 1;
+                e--;
+            }
+        }
+        return e;
+    }
+
+    /** Mantissa of a nonzero finite float including the implicit bit, normalized to bit 23. */
+    static int mantissa(int x) {
+        int m = x & 0x7fffff;
+        if ((x & 0x7f800000) == 0) {
+            while (m < 0x800000) {
+                m <<= 1;
+            }
+            return m;
+        }
+        return m | 0x800000;
+    }
+
+    static int fmul(int a, int b) {
+        int sign = (a ^ b) & 0x80000000;
+        if (isnan(a) || isnan(b)) {
+            return 0x7fc00000;
+        }
+        boolean zero = (a & 0x7fffffff) == 0 || (b & 0x7fffffff) == 0;
+        if ((a & 0x7f800000) == 0x7f800000 || (b & 0x7f800000) == 0x7f800000) {
+            return zero ? 0x7fc00000 : sign | 0x7f800000;
+        }
+        if (zero) {
+            return sign;
+        }
+        int ma = mantissa(a);
+        int mb = mantissa(b);
+        // 48-bit product of the mantissas from 12-bit partial products
+        int a0 = ma & 0xfff;
+        int a1 = ma >>> 12;
+        int b0 = mb & 0xfff;
+        int b1 = mb >>> 12;
+        int mid = a0 * b1 + a1 * b0;
+        int low = a0 * b0 + ((mid & 0xfff) << 12);
+        int high = a1 * b1 + (mid >>> 12) + (low >>> 24);
+        int m = (high << 4) | ((low & 0xffffff) >>> 20);
+        if ((low & 0xfffff) != 0) {
+            m |= 1;
+        }
+        return round(sign, exponent(a) + exponent(b) - 127, m);
+    }
+
+    static int fdiv(int a, int b) {
+        int sign = (a ^ b) & 0x80000000;
+        if (isnan(a) || isnan(b)) {
+            return 0x7fc00000;
+        }
+        boolean ainf = (a & 0x7f800000) == 0x7f800000;
+        boolean binf = (b & 0x7f800000) == 0x7f800000;
+        boolean azero = (a & 0x7fffffff) == 0;
+        boolean bzero = (b & 0x7fffffff) == 0;
+        if ((ainf && binf) || (azero && bzero)) {
+            return 0x7fc00000;
+        }
+        if (ainf || bzero) {
+            return sign | 0x7f800000;
+        }
+        if (binf || azero) {
+            return sign;
+        }
+        int rem = mantissa(a);
+        int mb = mantissa(b);
+        int q = 0;
+        for (int i = 0; i < 27; i++) {
+            q <<= 1;
+            if (rem >= mb) {
+                rem -= mb;
+                q |= 1;
+            }
+            rem <<= 1;
+        }
+        if (rem != 0) {
+            q |= 1;
+        }
+        return round(sign, exponent(a) - exponent(b) + 127, q);
+    }
+
+    /** Comparison of two non-NaN floats. */
+    static int fcmp(int a, int b) {
+        if (((a | b) & 0x7fffffff) == 0) {
+            return 0;
+        }
+        // order the bit patterns of negative floats like ints
+        a ^= (a >> 31) & 0x7fffffff;
+        b ^= (b >> 31) & 0x7fffffff;
+        if (a == b) {
+            return 0;
+        }
+        return a < b ? -1 : 1;
+    }
+
+    static int fcmpl(int a, int b) {
+        return isnan(a) || isnan(b) ? -1 : fcmp(a, b);
+    }
+
+    static int fcmpg(int a, int b) {
+        return isnan(a) || isnan(b) ? 1 : fcmp(a, b);
+    }
+
+    static int i2f(int x) {
+        if (x < 0) {
+            // the magnitude of the minimum int is correct as unsigned value
+            return round(0x80000000, 153, -x);
+        }
+        return round(0, 153, x);
+    }
+
+    static int f2i(int x) {
+        if (isnan(x)) {
+            return 0;
+        }
+        int e = (x >>> 23) & 0xff;
+        if (e < 127) {
+            return 0;
+        }
+        if (e >= 158) {
+            return x < 0 ? 0x80000000 : 0x7fffffff;
+        }
+        int m = (x & 0x7fffff) | 0x800000;
+        int r = e >= 150 ? m << (e - 150) : m >>> (150 - e);
+        return x < 0 ? -r : r;
+    }
+
+    /** Lowering of fcmpl and fcmpg on fixed-point floats. */
+    static int icmp(int a, int b) {
+        if (a == b) {
+            return 0;
+        }
+        return a < b ? -1 : 1;
+    }
+
+    /*
+     * Fallbacks for int instructions missing from the target, built from shifts, additions and logical operations.
+     * They must not use the instructions they replace.
+     */
+
+    /** Lowering of imul by shift-and-add. */
+    static int imul(int a, int b) {
+        int r = 0;
+        while (b != 0) {
+            if ((b & 1) != 0) {
+                r += a;
+            }
+            a <<= 1;
+            b >>>= 1;
+        }
+        return r;
+    }
+
+    /** Lowering of ishr, shifting in copies of the sign bit. */
+    static int ishr(int a, int s) {
+        s &= 31;
+        return a < 0 ? ~(~a >>> s) : a >>> s;
+    }
+
+    /** Unsigned shift-and-subtract division of n by d, returning the remainder if rem is set, else the quotient. */
+    static int udivmod(int n, int d, boolean rem) {
+        int q = 0;
+        int r = 0;
+        for (int i = 31; i >= 0; i--) {
+            r = (r << 1) | ((n >>> i) & 1);
+            q <<= 1;
+            if (!ult(r, d)) {
+                r -= d;
+                q |= 1;
+            }
+        }
+        return rem ? r : q;
+    }
+
+    /** Lowering of idiv, rounding towards zero. Division by zero yields -1 or 1 instead of an exception. */
+    static int idiv(int a, int b) {
+        int q = udivmod(a < 0 ? -a : a, b < 0 ? -b : b, false);
+        return (a ^ b) < 0 ? -q : q;
+    }
+
+    /** Lowering of irem, with the sign of the dividend. Division by zero yields the dividend. */
+    static int irem(int a, int b) {
+        int r = udivmod(a < 0 ? -a : a, b < 0 ? -b : b, true);
+        return a < 0 ? -r : r;
+    }
+
+    /**
+     * Division of Q16.16 fixed-point values, rounding towards zero. The 48-bit dividend a << 16 is divided by
+     * shift-and-subtract, so no precision is lost. Quotients beyond the Q16.16 range wrap around, and division
+     * by zero yields the largest value with the sign of the dividend.
+     */
+    static int fixdiv(int a, int b) {
+        if (b == 0) {
+            return a < 0 ? Integer.MIN_VALUE : Integer.MAX_VALUE;
+        }
+        int n = a < 0 ? -a : a;
+        int d = b < 0 ? -b : b;
+        int q = 0;
+        int r = 0;
+        for (int i = 47; i >= 0; i--) {
+            r = (r << 1) | (i >= 16 ? (n >>> (i - 16)) & 1 : 0);
+            q <<= 1;
+            if (!ult(r, d)) {
+                r -= d;
+                q |= 1;
+            }
+        }
+        return (a ^ b) < 0 ? -q : q;
+    }
+
+    /** Lowering of multianewarray with two dimensions. */
+    static int[][] newarray2(int n, int m) {
+        int[][] array = new int[n][];
+        for (int i = 0; i < n; i++) {
+            array[i] = new int[m];
+        }
+        return array;
+    }
+
+    /** Lowering of multianewarray with three dimensions. */
+    static int[][][] newarray3(int n, int m, int k) {
+        int[][][] array = new int[n][][];
+        for (int i = 0; i < n; i++) {
+            array[i] = newarray2(m, k);
+        }
+        return array;
+    }
+}
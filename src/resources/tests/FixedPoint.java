@@ -0,0 +1,17 @@
+public class FixedPoint {
+    public static void main(String[] args) {
+        float a = 200f;
+        float b = 4f;
+        float one = 1f;
+        float small = 0.001f;
+        float c = -3.5f;
+        int[] results = new int[7];
+        results[0] = (int) (a / b);
+        results[1] = (int) (one / small);
+        results[2] = (int) (-7f / b * 2f);
+        results[3] = (int) (a / -3f);
+        results[4] = (int) c;
+        results[5] = (int) (c * 2f);
+        results[6] = (int) (one / 0f);
+    }
+}
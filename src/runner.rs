@@ -0,0 +1,318 @@
+use crate::memory::{binarygen, Options, MAIN_SIG};
+use crate::sim::simulate;
+use crate::structs::{codeblocks, read_classfile, read_classset};
+use crate::uart::{binwrite, open_serial, readback, Protocol, Readback};
+
+use serde::Serialize;
+
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+/// Expected results of a test program, read from the `.expect` sidecar file next to its class file.
+#[derive(Default)]
+pub struct Expectation {
+    pub max_cycles: Option<u64>,
+    pub readbacks: Vec<(Readback, Vec<i32>)>,
+}
+
+/// Values read back for a single readback request, along with the expected values.
+#[derive(Serialize)]
+pub struct ReadbackResult {
+    pub request: String,
+    pub expected: Vec<i32>,
+    pub received: Vec<i32>,
+}
+
+/// Outcome of running a single test program.
+#[derive(Serialize)]
+pub struct TestResult {
+    pub name: String,
+    pub passed: bool,
+    pub cycles: Option<u64>,
+    pub readbacks: Vec<ReadbackResult>,
+    pub failure: Option<String>,
+}
+
+///
+/// Parses a sidecar file of expected test results.
+///
+/// Each line has the form `KEY = VALUES`, where `KEY` is either `max_cycles` or a readback request
+/// (`tos`, `output`, `mem:START:COUNT`) and `VALUES` is a comma-separated list of expected words.
+/// Empty lines and lines starting with `#` are ignored.
+///
+pub fn read_expectation(path: &Path) -> Result<Expectation, Error> {
+    let content = fs::read_to_string(path)?;
+    let mut expectation = Expectation::default();
+    let error = |line: &str| Error::other(format!("invalid expectation '{}'", line));
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, values) = line.split_once('=').ok_or_else(|| error(line))?;
+        let (key, values) = (key.trim(), values.trim());
+
+        if key == "max_cycles" {
+            expectation.max_cycles = Some(values.parse().map_err(|_| error(line))?);
+        } else {
+            let request: Readback = key.parse().map_err(|_| error(line))?;
+            let words = values
+                .split(',')
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| v.trim().parse::<i32>())
+                .collect::<Result<Vec<i32>, _>>()
+                .map_err(|_| error(line))?;
+            expectation.readbacks.push((request, words));
+        }
+    }
+
+    Ok(expectation)
+}
+
+fn execute(
+    classfile: &Path,
+    device: Option<&str>,
+    options: &Options,
+    protocol: &Protocol,
+    requests: &[&Readback],
+) -> Result<(u64, Vec<Vec<i32>>), Error> {
+    let classes = read_classset(classfile.to_str().unwrap())?;
+    let binary = binarygen(&classes, options)?;
+
+    match device {
+        Some(device) => {
+            let mut port = open_serial(device)?;
+            let mut received = Vec::new();
+            let cycles = binwrite(&mut port, &binary, protocol, &mut received)?;
+            let mut values = Vec::new();
+            for request in requests {
+                values.push(readback(&mut port, request, protocol, &mut received)?);
+            }
+            Ok((cycles, values))
+        }
+        None => {
+            let result = simulate(&binary, &options.target.lut)?;
+            let values = requests
+                .iter()
+                .map(|r| result.readback(r))
+                .collect::<Result<Vec<Vec<i32>>, Error>>()?;
+            Ok((result.cycles, values))
+        }
+    }
+}
+
+///
+/// Compiles a class file with the given options, runs it on the given serial device (or the simulator if no device is given)
+/// and compares the results against the expectations in the `.expect` sidecar file, if present.
+///
+pub fn run_test(
+    classfile: &Path,
+    device: Option<&str>,
+    options: &Options,
+    protocol: &Protocol,
+) -> TestResult {
+    let name = classfile.file_stem().unwrap().to_string_lossy().to_string();
+    let mut result = TestResult {
+        name,
+        passed: false,
+        cycles: None,
+        readbacks: Vec::new(),
+        failure: None,
+    };
+
+    let sidecar = classfile.with_extension("expect");
+    let expectation = if sidecar.exists() {
+        match read_expectation(&sidecar) {
+            Ok(expectation) => expectation,
+            Err(err) => {
+                result.failure = Some(err.to_string());
+                return result;
+            }
+        }
+    } else {
+        Expectation::default()
+    };
+
+    let requests: Vec<&Readback> = expectation.readbacks.iter().map(|(r, _)| r).collect();
+    let (cycles, values) = match execute(classfile, device, options, protocol, &requests) {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            result.failure = Some(err.to_string());
+            return result;
+        }
+    };
+
+    result.cycles = Some(cycles);
+    let mut failures: Vec<String> = Vec::new();
+
+    if let Some(max_cycles) = expectation.max_cycles {
+        if cycles > max_cycles {
+            failures.push(format!("{} cycles exceed maximum of {}", cycles, max_cycles));
+        }
+    }
+
+    for ((request, expected), received) in expectation.readbacks.iter().zip(values) {
+        if *expected != received {
+            failures.push(format!(
+                "{}: expected {:?}, received {:?}",
+                request, expected, received
+            ));
+        }
+        result.readbacks.push(ReadbackResult {
+            request: request.to_string(),
+            expected: expected.to_vec(),
+            received,
+        });
+    }
+
+    result.passed = failures.is_empty();
+    if !failures.is_empty() {
+        result.failure = Some(failures.join("; "));
+    }
+
+    result
+}
+
+///
+/// Returns whether the class file has a `main` method. Unreadable class files are run as tests,
+/// so their errors are reported.
+///
+fn has_main(classfile: &Path) -> bool {
+    read_classfile(classfile.to_str().unwrap())
+        .and_then(|class| codeblocks(&class))
+        .map_or(true, |methods| methods.contains_key(MAIN_SIG))
+}
+
+///
+/// Runs every class file in the given directory as a test program, in alphabetical order.
+///
+/// Class files without a `main` method, e.g. data classes used by the test programs, are not run
+/// and only read as members of the class sets of the programs referencing them.
+///
+pub fn run_tests(
+    dir: &Path,
+    device: Option<&str>,
+    options: &Options,
+    protocol: &Protocol,
+) -> Result<Vec<TestResult>, Error> {
+    let mut classfiles: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "class") && has_main(path))
+        .collect();
+    classfiles.sort();
+
+    Ok(classfiles
+        .iter()
+        .map(|classfile| run_test(classfile, device, options, protocol))
+        .collect())
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+///
+/// Formats test results as a JUnit XML report.
+///
+pub fn junit_report(results: &[TestResult]) -> String {
+    let failures = results.iter().filter(|r| !r.passed).count();
+    let mut report = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+
+    report.push_str(&format!(
+        "<testsuite name=\"bake\" tests=\"{}\" failures=\"{}\">\n",
+        results.len(),
+        failures
+    ));
+    for result in results {
+        report.push_str(&format!(
+            "  <testcase name=\"{}\" classname=\"bake\">\n",
+            xml_escape(&result.name)
+        ));
+        // the JUnit schema orders failures before the output
+        if let Some(failure) = &result.failure {
+            report.push_str(&format!(
+                "    <failure message=\"{}\"/>\n",
+                xml_escape(failure)
+            ));
+        }
+        if let Some(cycles) = result.cycles {
+            report.push_str(&format!("    <system-out>{} cycles</system-out>\n", cycles));
+        }
+        report.push_str("  </testcase>\n");
+    }
+    report.push_str("</testsuite>\n");
+
+    report
+}
+
+///
+/// Formats test results as a JSON report.
+///
+pub fn json_report(results: &[TestResult]) -> String {
+    serde_json::to_string_pretty(results).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::runtime_class;
+    use crate::structs::write_classfile;
+
+    fn result(name: &str, failure: Option<&str>) -> TestResult {
+        TestResult {
+            name: name.to_string(),
+            passed: failure.is_none(),
+            cycles: Some(42),
+            readbacks: Vec::new(),
+            failure: failure.map(|failure| failure.to_string()),
+        }
+    }
+
+    #[test]
+    fn junit() {
+        let report = junit_report(&[
+            result("Sum", None),
+            result("Bad", Some("tos: expected [1], received [\"2\" & <3>]")),
+        ]);
+
+        assert!(report.contains("<testsuite name=\"bake\" tests=\"2\" failures=\"1\">"));
+        assert!(report.contains(
+            "<failure message=\"tos: expected [1], received [&quot;2&quot; &amp; &lt;3&gt;]\"/>"
+        ));
+        let bad = &report[report.find("\"Bad\"").unwrap()..];
+        assert!(bad.find("<failure").unwrap() < bad.find("<system-out>").unwrap());
+    }
+
+    #[test]
+    fn json() {
+        let report: serde_json::Value =
+            serde_json::from_str(&json_report(&[result("Sum", None)])).unwrap();
+
+        assert_eq!(report[0]["name"], "Sum");
+        assert_eq!(report[0]["passed"], true);
+        assert_eq!(report[0]["cycles"], 42);
+    }
+
+    #[test]
+    fn classes_without_main() {
+        let dir = std::env::temp_dir().join(format!("bake-tests-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = include_bytes!("resources/tests/FixedPoint.class");
+        fs::write(dir.join("FixedPoint.class"), program).unwrap();
+        let data = dir.join("Data.class");
+        write_classfile(data.to_str().unwrap(), &mut runtime_class()).unwrap();
+
+        let results = run_tests(&dir, None, &Options::default(), &Protocol::default());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let results = results.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].name, "FixedPoint");
+        assert!(results[0].passed);
+    }
+}
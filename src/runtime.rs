@@ -0,0 +1,23 @@
+use crate::structs::ClassFile;
+
+use binrw::BinReaderExt;
+
+use std::io::Cursor;
+
+/// Binary name of the class containing the runtime helper methods.
+pub const RUNTIME_CLASS: &str = "BakeRuntime";
+
+///
+/// Parses the runtime helper class, compiled from `resources/runtime/BakeRuntime.java`.
+///
+pub fn runtime_class() -> ClassFile {
+    let classfile = include_bytes!("resources/runtime/BakeRuntime.class");
+    Cursor::new(&classfile[..]).read_be().unwrap()
+}
+
+///
+/// Returns the name of the runtime helper method with the given signature, as it appears in the method LUT.
+///
+pub fn helper(sig: &str) -> String {
+    format!("{}.{}", RUNTIME_CLASS, sig)
+}
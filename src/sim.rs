@@ -0,0 +1,399 @@
+use crate::memory::LUTENTRY;
+use crate::opcodes::opmap;
+use crate::target::LutFormat;
+use crate::uart::Readback;
+
+use std::io::Error;
+
+/// Bytecode value the main method's `return` is replaced with, stops execution.
+const HALT: u8 = 0xFF;
+
+/// Number of instructions after which a simulation run is aborted.
+const MAX_STEPS: u64 = 100_000_000;
+
+/// Results of a simulated Bali program run, mirroring the values a Bali device reports back.
+pub struct SimResult {
+    pub cycles: u64,
+    pub top_of_stack: i32,
+    pub memory: Vec<i32>,
+}
+
+/// Method call frame, consisting of the return address and the local variable array of the method.
+struct Frame {
+    retaddr: usize,
+    locals: Vec<i32>,
+}
+
+/// Number of words addressable in static memory.
+const HEAP_WORDS: usize = 1 << 16;
+
+fn read(bin: &[u8], addr: usize, len: usize) -> Result<&[u8], Error> {
+    bin.get(addr..addr + len).ok_or_else(|| {
+        Error::other(format!(
+            "read of {} bytes at {:#06x} exceeds the binary of {} bytes",
+            len,
+            addr,
+            bin.len()
+        ))
+    })
+}
+
+fn read_u16(bin: &[u8], addr: usize) -> Result<u16, Error> {
+    let bytes = read(bin, addr, 2)?;
+    Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+}
+
+fn read_i32(bin: &[u8], addr: usize) -> Result<i32, Error> {
+    let bytes = read(bin, addr, 4)?;
+    Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn local(locals: &mut [i32], index: usize) -> Result<&mut i32, Error> {
+    let count = locals.len();
+    locals.get_mut(index).ok_or_else(|| {
+        Error::other(format!(
+            "local variable {} exceeds the {} local variables of the method",
+            index, count
+        ))
+    })
+}
+
+/// Evaluation stack memory. Popped values stay in memory, as they do in the Bali stack memory.
+struct Stack {
+    mem: Vec<i32>,
+    sp: usize,
+}
+
+impl Stack {
+    fn push(&mut self, value: i32) {
+        if self.sp == self.mem.len() {
+            self.mem.push(value);
+        } else {
+            self.mem[self.sp] = value;
+        }
+        self.sp += 1;
+    }
+
+    fn pop(&mut self) -> Result<i32, Error> {
+        if self.sp == 0 {
+            return Err(Error::other("evaluation stack underflow"));
+        }
+        self.sp -= 1;
+        Ok(self.mem[self.sp])
+    }
+
+    fn top(&self) -> i32 {
+        *self.mem.get(self.sp.saturating_sub(1)).unwrap_or(&0)
+    }
+}
+
+fn array_index(memory: &[i32], arrayref: i32, index: i32) -> Result<usize, Error> {
+    let base = arrayref as usize;
+    match memory.get(base) {
+        Some(length) if index >= 0 && index < *length => Ok(base + 1 + index as usize),
+        Some(_) => Err(Error::other(format!(
+            "array index {} out of bounds for array at {:#06x}",
+            index, base
+        ))),
+        None => Err(Error::other(format!("invalid array reference {:#06x}", base))),
+    }
+}
+
+///
+/// Executes a Bali binary the way the Bali processor would, starting at the `main` method (LUT index 0).
+///
+/// Arrays are allocated in static memory as a length word followed by the array elements,
+/// array references are the static memory address of the length word.
+/// The cycle count is the number of executed instructions.
+///
+/// Method LUT entries are read in the given format.
+/// Returns the results of the run or an error if the program performs an invalid operation.
+///
+pub fn simulate(bin: &[u8], lut: &LutFormat) -> Result<SimResult, Error> {
+    let opmap = opmap();
+
+    let mut stack = Stack {
+        mem: Vec::new(),
+        sp: 0,
+    };
+    let mut memory: Vec<i32> = Vec::new();
+    let mut frames: Vec<Frame> = Vec::new();
+
+    let (mut pc, _, main_locals) = lut.unpack(read(bin, 0, LUTENTRY)?);
+    let mut locals: Vec<i32> = vec![0; main_locals];
+    let mut cycles: u64 = 0;
+
+    loop {
+        if cycles >= MAX_STEPS {
+            return Err(Error::other("simulation step limit exceeded"));
+        }
+        cycles += 1;
+
+        let opcode = *bin
+            .get(pc)
+            .ok_or_else(|| Error::other(format!("program counter {:#06x} out of bounds", pc)))?;
+        if opcode == HALT {
+            break;
+        }
+
+        let op = opmap
+            .get(&opcode)
+            .ok_or_else(|| Error::other(format!("unknown opcode {:#04x} at {:#06x}", opcode, pc)))?;
+        let arg8 = || read(bin, pc + 1, 1).map(|bytes| bytes[0]);
+        let arg16 = || read_u16(bin, pc + 1);
+        let mut nextpc = pc + 1 + op.args as usize;
+
+        match op.mnemonic.as_str() {
+            "nop" => {}
+            "aconst_null" => stack.push(0),
+            "iconst_m1" | "iconst_0" | "iconst_1" | "iconst_2" | "iconst_3" | "iconst_4"
+            | "iconst_5" => stack.push(opcode as i32 - 0x03),
+            "bipush" => stack.push(arg8()? as i8 as i32),
+            "sipush" => stack.push(arg16()? as i16 as i32),
+            "ldc" => stack.push(read_i32(bin, arg8()? as usize * LUTENTRY)?),
+            "iload" | "aload" => stack.push(*local(&mut locals, arg8()? as usize)?),
+            "iload_0" | "iload_1" | "iload_2" | "iload_3" => {
+                stack.push(*local(&mut locals, (opcode - 0x1a) as usize)?)
+            }
+            "aload_0" | "aload_1" | "aload_2" | "aload_3" => {
+                stack.push(*local(&mut locals, (opcode - 0x2a) as usize)?)
+            }
+            "istore" | "astore" => *local(&mut locals, arg8()? as usize)? = stack.pop()?,
+            "istore_0" | "istore_1" | "istore_2" | "istore_3" => {
+                *local(&mut locals, (opcode - 0x3b) as usize)? = stack.pop()?
+            }
+            "astore_0" | "astore_1" | "astore_2" | "astore_3" => {
+                *local(&mut locals, (opcode - 0x4b) as usize)? = stack.pop()?
+            }
+            "iaload" | "aaload" | "baload" | "caload" | "saload" => {
+                let index = stack.pop()?;
+                let arrayref = stack.pop()?;
+                stack.push(memory[array_index(&memory, arrayref, index)?]);
+            }
+            "iastore" | "aastore" | "bastore" | "castore" | "sastore" => {
+                let value = stack.pop()?;
+                let index = stack.pop()?;
+                let arrayref = stack.pop()?;
+                let addr = array_index(&memory, arrayref, index)?;
+                memory[addr] = match op.mnemonic.as_str() {
+                    "bastore" => value as i8 as i32,
+                    "castore" => value as u16 as i32,
+                    "sastore" => value as i16 as i32,
+                    _ => value,
+                };
+            }
+            "pop" => {
+                stack.pop()?;
+            }
+            "dup" => {
+                let value = stack.pop()?;
+                stack.push(value);
+                stack.push(value);
+            }
+            "swap" => {
+                let a = stack.pop()?;
+                let b = stack.pop()?;
+                stack.push(a);
+                stack.push(b);
+            }
+            "ineg" => {
+                let value = stack.pop()?;
+                stack.push(value.wrapping_neg());
+            }
+            "iadd" | "isub" | "imul" | "idiv" | "irem" | "ishl" | "ishr" | "iushr" | "iand"
+            | "ior" | "ixor" => {
+                let b = stack.pop()?;
+                let a = stack.pop()?;
+                if b == 0 && (op.mnemonic == "idiv" || op.mnemonic == "irem") {
+                    return Err(Error::other(format!("division by zero at {:#06x}", pc)));
+                }
+                stack.push(match op.mnemonic.as_str() {
+                    "iadd" => a.wrapping_add(b),
+                    "isub" => a.wrapping_sub(b),
+                    "imul" => a.wrapping_mul(b),
+                    "idiv" => a.wrapping_div(b),
+                    "irem" => a.wrapping_rem(b),
+                    "ishl" => a.wrapping_shl(b as u32 & 0x1f),
+                    "ishr" => a.wrapping_shr(b as u32 & 0x1f),
+                    "iushr" => ((a as u32) >> (b as u32 & 0x1f)) as i32,
+                    "iand" => a & b,
+                    "ior" => a | b,
+                    _ => a ^ b,
+                });
+            }
+            "iinc" => {
+                let increment = read(bin, pc + 2, 1)?[0] as i8 as i32;
+                let value = local(&mut locals, arg8()? as usize)?;
+                *value = value.wrapping_add(increment);
+            }
+            "ifeq" | "ifne" | "iflt" | "ifge" | "ifgt" | "ifle" | "if_icmpeq" | "if_icmpne"
+            | "if_icmplt" | "if_icmpge" | "if_icmpgt" | "if_icmple" | "goto" => {
+                let taken = match op.mnemonic.as_str() {
+                    "goto" => true,
+                    mnemonic if mnemonic.starts_with("if_icmp") => {
+                        let b = stack.pop()?;
+                        let a = stack.pop()?;
+                        compare(&mnemonic[7..], a, b)
+                    }
+                    mnemonic => compare(&mnemonic[2..], stack.pop()?, 0),
+                };
+                if taken {
+                    nextpc = (pc as isize + arg16()? as i16 as isize) as usize;
+                }
+            }
+            "invokestatic" | "invokespecial" => {
+                let entry = arg16()? as usize * LUTENTRY;
+                let (addr, argcount, max_locals) = lut.unpack(read(bin, entry, LUTENTRY)?);
+                let mut callee: Vec<i32> = vec![0; max_locals.max(argcount)];
+                for i in (0..argcount).rev() {
+                    callee[i] = stack.pop()?;
+                }
+                frames.push(Frame {
+                    retaddr: nextpc,
+                    locals: std::mem::replace(&mut locals, callee),
+                });
+                nextpc = addr;
+            }
+            "ireturn" | "areturn" | "return" => {
+                let frame = frames
+                    .pop()
+                    .ok_or_else(|| Error::other("return from main method"))?;
+                locals = frame.locals;
+                nextpc = frame.retaddr;
+            }
+            "newarray" => {
+                let length = stack.pop()?;
+                if length < 0 {
+                    return Err(Error::other(format!("negative array size at {:#06x}", pc)));
+                }
+                stack.push(memory.len() as i32);
+                memory.push(length);
+                memory.resize(memory.len() + length as usize, 0);
+            }
+            "arraylength" => {
+                let arrayref = stack.pop()? as usize;
+                let length = *memory.get(arrayref).ok_or_else(|| {
+                    Error::other(format!("invalid array reference {:#06x}", arrayref))
+                })?;
+                stack.push(length);
+            }
+            mnemonic => {
+                return Err(Error::other(format!(
+                    "instruction {} not supported by the simulator",
+                    mnemonic
+                )))
+            }
+        }
+
+        pc = nextpc;
+    }
+
+    Ok(SimResult {
+        cycles,
+        top_of_stack: stack.top(),
+        memory,
+    })
+}
+
+fn compare(condition: &str, a: i32, b: i32) -> bool {
+    match condition {
+        "eq" => a == b,
+        "ne" => a != b,
+        "lt" => a < b,
+        "ge" => a >= b,
+        "gt" => a > b,
+        _ => a <= b,
+    }
+}
+
+impl SimResult {
+    ///
+    /// Selects the simulated values corresponding to a device readback request.
+    ///
+    /// Memory words outside of the allocated static memory read as zero, the range is clamped to the
+    /// addressable static memory. Returns an error for the output buffer, which the simulator does not model.
+    ///
+    pub fn readback(&self, request: &Readback) -> Result<Vec<i32>, Error> {
+        match request {
+            Readback::TopOfStack => Ok(vec![self.top_of_stack]),
+            Readback::Memory(start, count) => {
+                let start = *start as usize;
+                let end = (start + *count as usize).min(HEAP_WORDS);
+                Ok((start..end)
+                    .map(|addr| *self.memory.get(addr).unwrap_or(&0))
+                    .collect())
+            }
+            Readback::Output => Err(Error::other(
+                "output readback is not supported in simulation, the simulator does not model the device output buffer",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Builds a binary of a single `main` method with the given code and local variable count.
+    ///
+    fn program(code: &[u8], locals: usize) -> Vec<u8> {
+        let lut = LutFormat::default();
+        let mut bin = lut.pack(LUTENTRY, 0, locals).unwrap().to_vec();
+        bin.extend_from_slice(code);
+        bin
+    }
+
+    #[test]
+    fn arithmetic() {
+        // bipush 20; bipush 22; iadd; istore_0; iload_0; halt
+        let bin = program(&[0x10, 20, 0x10, 22, 0x60, 0x3b, 0x1a, HALT], 1);
+        let result = simulate(&bin, &LutFormat::default()).unwrap();
+
+        assert_eq!(result.cycles, 6);
+        assert_eq!(result.top_of_stack, 42);
+    }
+
+    #[test]
+    fn branch() {
+        // iconst_3; istore_0; loop: iinc 0 -1; iload_0; ifne loop; iload_0; bipush 7; iadd; halt
+        let code = [
+            0x06, 0x3b, 0x84, 0, 0xff, 0x1a, 0x9a, 0xff, 0xfc, 0x1a, 0x10, 7, 0x60, HALT,
+        ];
+        let result = simulate(&program(&code, 1), &LutFormat::default()).unwrap();
+
+        assert_eq!(result.cycles, 2 + 3 * 3 + 4);
+        assert_eq!(result.top_of_stack, 7);
+    }
+
+    #[test]
+    fn arrays() {
+        // iconst_2; newarray int; dup; iconst_1; bipush 9; iastore; halt
+        let bin = program(&[0x05, 0xbc, 10, 0x59, 0x04, 0x10, 9, 0x4f, HALT], 0);
+        let result = simulate(&bin, &LutFormat::default()).unwrap();
+
+        assert_eq!(result.memory, vec![2, 0, 9]);
+        assert_eq!(
+            result.readback(&Readback::Memory(1, 3)).unwrap(),
+            vec![0, 9, 0]
+        );
+        assert_eq!(
+            result.readback(&Readback::Memory(u16::MAX, 10)).unwrap(),
+            vec![0]
+        );
+        assert!(result.readback(&Readback::Output).is_err());
+    }
+
+    #[test]
+    fn invalid_programs() {
+        let lut = LutFormat::default();
+
+        assert!(simulate(&[], &lut).is_err());
+        // bipush without operand
+        assert!(simulate(&program(&[0x10], 0), &lut).is_err());
+        // iload_1 with a single local variable
+        assert!(simulate(&program(&[0x1b, HALT], 1), &lut).is_err());
+        // iconst_1; iconst_0; idiv
+        assert!(simulate(&program(&[0x04, 0x03, 0x6c, HALT], 0), &lut).is_err());
+    }
+}
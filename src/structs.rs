@@ -1,70 +1,84 @@
-use binrw::{binrw, BinReaderExt};
+use crate::attributes::{decode_attribute, decode_attributes, Attribute, CodeAttribute};
+use crate::descriptor::MethodDescriptor;
+use crate::target::LutFormat;
+
+use binrw::io::{Read, Seek};
+use binrw::{binrw, BinRead, BinReaderExt, BinResult, BinWriterExt, ReadOptions};
 
 use bimap::BiBTreeMap;
 use std::collections::btree_map::BTreeMap;
 
 use std::fs::File;
-use std::io::{Cursor, Error, ErrorKind};
+use std::io::Error;
+use std::path::Path;
 
 #[binrw]
+#[derive(PartialEq)]
 /// Structures containing constants, metadata of the JVM class file.
 pub enum ConstPoolInfo {
-    #[br(magic(7u8))]
+    #[brw(magic(7u8))]
     ConstClass { name_index: u16 },
-    #[br(magic(9u8))]
+    #[brw(magic(9u8))]
     ConstFieldRef {
         class_index: u16,
         name_and_type_index: u16,
     },
-    #[br(magic(10u8))]
+    #[brw(magic(10u8))]
     ConstMethodRef {
         class_index: u16,
         name_and_type_index: u16,
     },
-    #[br(magic(11u8))]
+    #[brw(magic(11u8))]
     ConstInterfaceMethodRef {
         class_index: u16,
         name_and_type_index: u16,
     },
-    #[br(magic(8u8))]
+    #[brw(magic(8u8))]
     ConstString { string_index: u16 },
-    #[br(magic(3u8))]
+    #[brw(magic(3u8))]
     ConstInt { bytes: u32 },
-    #[br(magic(4u8))]
+    #[brw(magic(4u8))]
     ConstFloat { bytes: u32 },
-    #[br(magic(5u8))]
+    #[brw(magic(5u8))]
     ConstLong { high_bytes: u32, low_bytes: u32 },
-    #[br(magic(6u8))]
+    #[brw(magic(6u8))]
     ConstDouble { high_bytes: u32, low_bytes: u32 },
-    #[br(magic(12u8))]
+    #[brw(magic(12u8))]
     ConstNameAndType {
         name_index: u16,
         descriptor_index: u16,
     },
-    #[br(magic(1u8))]
+    #[brw(magic(1u8))]
     ConstUTF8 {
         length: u16,
         #[br(count = length)]
         bytes: Vec<u8>,
     },
-    #[br(magic(15u8))]
+    #[brw(magic(15u8))]
     ConstMethodHandle {
         reference_kind: u8,
         reference_index: u16,
     },
-    #[br(magic(16u8))]
+    #[brw(magic(16u8))]
     ConstMethodType { descriptor_index: u16 },
-    #[br(magic(18u8))]
+    #[brw(magic(18u8))]
     ConstInvokeDynamic {
         bootstrap_method_attr_index: u16,
         name_and_type_index: u16,
     },
+    /// Unusable constant pool slot following a `ConstLong` or `ConstDouble` entry,
+    /// never read or written on its own.
+    #[br(pre_assert(false))]
+    ConstUnusable,
 }
 
 /// Structures for constant pool objects needed for Bali binaries.
 pub enum ConstPoolValue {
     Class(u16),
     Integer(i32),
+    Float(f32),
+    Long(i64),
+    FieldRef(u16, u16),
     MethodRef(u16, u16),
     NameAndType(u16, u16),
     UTF8String(String),
@@ -81,6 +95,13 @@ pub struct FieldInfo {
     attributes: Vec<AttributeInfo>,
 }
 
+/// Access flag marking private methods.
+pub const ACC_PRIVATE: u16 = 0x0002;
+/// Access flag marking static methods and fields.
+pub const ACC_STATIC: u16 = 0x0008;
+/// Access flag marking final methods.
+pub const ACC_FINAL: u16 = 0x0010;
+
 #[binrw]
 /// Structure containing information about the class methods.
 pub struct MethodInfo {
@@ -95,43 +116,108 @@ pub struct MethodInfo {
 #[binrw]
 /// Generic container structure for an attribute of a class, method, field, etc.
 pub struct AttributeInfo {
-    attribute_name_index: u16,
-    attribute_length: u32,
+    pub attribute_name_index: u16,
+    pub attribute_length: u32,
     #[br(count = attribute_length)]
-    info: Vec<u8>,
-}
-
-#[binrw]
-/// Exception table entry structure that forms part of a code attribute.
-pub struct ExceptionTableEntry {
-    start_pc: u16,
-    end_pc: u16,
-    handler_pc: u16,
-    catch_type: u16,
-}
-
-#[binrw]
-/// JVM class file code attribute structure.
-pub struct CodeAttribute {
-    max_stack: u16,
-    max_locals: u16,
-    code_length: u32,
-    #[br(count = code_length)]
-    code: Vec<u8>,
-    exception_table_length: u16,
-    #[br(count = exception_table_length)]
-    exception_table: Vec<ExceptionTableEntry>,
-    attributes_count: u16,
-    #[br(count = attributes_count)]
-    attributes: Vec<AttributeInfo>,
+    pub info: Vec<u8>,
 }
 
 /// Structure containing the relevant method information for the Bali processor.
 pub struct BaliCode {
+    pub access_flags: u16,
     pub max_stack: u16,
     pub max_locals: u16,
     pub argcount: u16,
     pub code: Vec<u8>,
+    /// Line number table of the method, as pairs of bytecode offset and source line.
+    pub lines: Vec<(u16, u16)>,
+    /// Local variable table of the method, empty if the class was compiled without `-g`.
+    pub locals: Vec<LocalVariable>,
+}
+
+impl BaliCode {
+    ///
+    /// Checks that the method frame fits the argument count and LVA size fields of the method LUT.
+    ///
+    /// Returns an error if the argument slots exceed the LVA size or the LVA size exceeds its LUT field.
+    ///
+    pub fn validate(&self, lut: &LutFormat) -> Result<(), Error> {
+        if self.argcount > self.max_locals {
+            return Err(Error::other(format!(
+                "{} argument slots exceed local variable array size of {}",
+                self.argcount, self.max_locals
+            )));
+        }
+        let maximum = (1_u64 << lut.locals_bits) - 1;
+        if self.max_locals as u64 > maximum {
+            return Err(Error::other(format!(
+                "local variable array size of {} exceeds maximum of {}",
+                self.max_locals, maximum
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Local variable of a method, valid for a range of bytecode offsets.
+pub struct LocalVariable {
+    pub start_pc: u16,
+    pub length: u16,
+    pub index: u16,
+    pub name: String,
+    pub descriptor: String,
+}
+
+impl BaliCode {
+    ///
+    /// Looks up the local variable with the given index accessed by the instruction at `pc`.
+    ///
+    /// The scope of a variable starts after the instruction initializing it,
+    /// so variables starting at the next instruction address `nextpc` are included.
+    ///
+    pub fn local(&self, index: u16, pc: u16, nextpc: u16) -> Option<&LocalVariable> {
+        self.locals.iter().find(|var| {
+            var.index == index && var.start_pc <= nextpc && pc < var.start_pc + var.length
+        })
+    }
+}
+
+/// Names of the attributes that only carry debug information and can be removed without changing behavior.
+const DEBUG_ATTRIBUTES: [&str; 5] = [
+    "SourceFile",
+    "SourceDebugExtension",
+    "LineNumberTable",
+    "LocalVariableTable",
+    "LocalVariableTypeTable",
+];
+
+///
+/// Creates a parser for a constant pool with the given constant pool count.
+///
+/// `ConstLong` and `ConstDouble` entries occupy two constant pool indices,
+/// so each of them is followed by a `ConstUnusable` entry to keep indices aligned.
+///
+fn constpool_parser<R: Read + Seek>(
+    count: u16,
+) -> impl Fn(&mut R, &ReadOptions, ()) -> BinResult<Vec<ConstPoolInfo>> {
+    move |reader, options, _| {
+        let mut constpool: Vec<ConstPoolInfo> = Vec::with_capacity(count as usize);
+
+        while constpool.len() + 1 < count as usize {
+            let info = ConstPoolInfo::read_options(reader, options, ())?;
+            let wide = matches!(
+                info,
+                ConstPoolInfo::ConstLong { .. } | ConstPoolInfo::ConstDouble { .. }
+            );
+            constpool.push(info);
+            if wide {
+                constpool.push(ConstPoolInfo::ConstUnusable);
+            }
+        }
+
+        Ok(constpool)
+    }
 }
 
 #[binrw]
@@ -141,7 +227,7 @@ pub struct ClassFile {
     minor_version: u16,
     major_version: u16,
     constpool_count: u16,
-    #[br(count = constpool_count - 1)]
+    #[br(parse_with = constpool_parser(constpool_count))]
     constpool: Vec<ConstPoolInfo>,
     access_flags: u16,
     this_class: u16,
@@ -169,11 +255,306 @@ pub fn read_classfile(path: &str) -> Result<ClassFile, Error> {
     let res = File::open(path);
 
     match res {
-        Ok(mut file) => Ok(file.read_be().unwrap()),
+        Ok(mut file) => file
+            .read_be()
+            .map_err(|err| Error::other(format!("malformed class file {}: {}", path, err))),
         Err(err) => Err(err),
     }
 }
 
+///
+/// Reads the class file at the given path along with all classes it references
+/// that are found as class files in the same directory, e.g. data classes used by the program.
+///
+/// Returns the class set, with the class given in the path first.
+///
+pub fn read_classset(path: &str) -> Result<Vec<ClassFile>, Error> {
+    let mut classes = vec![read_classfile(path)?];
+    let mut names = vec![this_class(&classes[0])];
+
+    let mut i = 0;
+    while i < classes.len() {
+        let referenced: Vec<String> = constants(&classes[i])
+            .keys()
+            .filter_map(|index| classname(&classes[i], *index))
+            .collect();
+
+        for name in referenced {
+            let simple_name = name.rsplit('/').next().unwrap();
+            let classpath = Path::new(path).with_file_name(format!("{}.class", simple_name));
+            if names.contains(&name) || !classpath.exists() {
+                continue;
+            }
+
+            let classinfo = read_classfile(classpath.to_str().unwrap())?;
+            if this_class(&classinfo) == name {
+                classes.push(classinfo);
+                names.push(name);
+            }
+        }
+        i += 1;
+    }
+
+    Ok(classes)
+}
+
+///
+/// Writes a `ClassFile` structure to a JVM class file at the given path.
+///
+/// All count and length fields are recomputed from the structure contents before writing.
+///
+pub fn write_classfile(path: &str, class: &mut ClassFile) -> Result<(), Error> {
+    class.update_lengths();
+
+    let mut file = File::create(path)?;
+    file.write_be(class)
+        .map_err(|err| Error::other(format!("could not write class file: {}", err)))
+}
+
+impl ClassFile {
+    fn update_lengths(&mut self) {
+        let update = |attributes: &mut Vec<AttributeInfo>| {
+            for attr in attributes.iter_mut() {
+                attr.attribute_length = attr.info.len() as u32;
+            }
+            attributes.len() as u16
+        };
+
+        self.constpool_count = self.constpool.len() as u16 + 1;
+        self.interfaces_count = self.interfaces.len() as u16;
+        self.fields_count = self.fields.len() as u16;
+        for field in &mut self.fields {
+            field.attributes_count = update(&mut field.attributes);
+        }
+        self.methods_count = self.methods.len() as u16;
+        for method in &mut self.methods {
+            method.attributes_count = update(&mut method.attributes);
+        }
+        self.attributes_count = update(&mut self.attributes);
+    }
+
+    ///
+    /// Inserts a constant into the constant pool, unless an identical constant already exists.
+    ///
+    /// Returns the constant pool index (1-based) of the constant,
+    /// or an error if the constant pool is full.
+    ///
+    pub fn insert_const(&mut self, info: ConstPoolInfo) -> Result<u16, Error> {
+        if let Some(i) = self.constpool.iter().position(|c| *c == info) {
+            return Ok(i as u16 + 1);
+        }
+
+        let wide = matches!(
+            info,
+            ConstPoolInfo::ConstLong { .. } | ConstPoolInfo::ConstDouble { .. }
+        );
+        let slots = if wide { 2 } else { 1 };
+        if self.constpool.len() + slots >= u16::MAX as usize {
+            return Err(Error::other("constant pool exceeds maximum size"));
+        }
+
+        self.constpool.push(info);
+        if wide {
+            self.constpool.push(ConstPoolInfo::ConstUnusable);
+        }
+        self.constpool_count = self.constpool.len() as u16 + 1;
+
+        Ok((self.constpool.len() + 1 - slots) as u16)
+    }
+
+    ///
+    /// Inserts a string constant into the constant pool, encoded as modified UTF-8.
+    ///
+    /// Returns an error if the encoded string exceeds the 65535 bytes a constant holds.
+    ///
+    pub fn insert_utf8(&mut self, value: &str) -> Result<u16, Error> {
+        let bytes = encode_modified_utf8(value);
+        let length = u16::try_from(bytes.len()).map_err(|_| {
+            Error::other(format!(
+                "string constant of {} bytes exceeds the maximum of {} bytes",
+                bytes.len(),
+                u16::MAX
+            ))
+        })?;
+
+        self.insert_const(ConstPoolInfo::ConstUTF8 { length, bytes })
+    }
+
+    ///
+    /// Inserts an integer constant into the constant pool.
+    ///
+    pub fn insert_int(&mut self, value: i32) -> Result<u16, Error> {
+        self.insert_const(ConstPoolInfo::ConstInt {
+            bytes: value as u32,
+        })
+    }
+
+    ///
+    /// Inserts a class reference, given by its binary class name, into the constant pool.
+    ///
+    pub fn insert_class(&mut self, name: &str) -> Result<u16, Error> {
+        let name_index = self.insert_utf8(name)?;
+        self.insert_const(ConstPoolInfo::ConstClass { name_index })
+    }
+
+    ///
+    /// Inserts a method reference, given by class name, method name and method descriptor, into the constant pool.
+    ///
+    pub fn insert_methodref(
+        &mut self,
+        class: &str,
+        name: &str,
+        descriptor: &str,
+    ) -> Result<u16, Error> {
+        let class_index = self.insert_class(class)?;
+        let name_index = self.insert_utf8(name)?;
+        let descriptor_index = self.insert_utf8(descriptor)?;
+        let name_and_type_index = self.insert_const(ConstPoolInfo::ConstNameAndType {
+            name_index,
+            descriptor_index,
+        })?;
+        self.insert_const(ConstPoolInfo::ConstMethodRef {
+            class_index,
+            name_and_type_index,
+        })
+    }
+
+    fn method_position(&self, sig: &str) -> Option<usize> {
+        let utf8_constpool = utf8_constants(self);
+
+        self.methods.iter().position(|method| {
+            let name = &utf8_constpool[&method.name_index];
+            let desc = &utf8_constpool[&method.descriptor_index];
+            sig.strip_prefix(name.as_str()) == Some(desc.as_str())
+        })
+    }
+
+    ///
+    /// Looks up the code attribute of the method with the given signature (method name followed by its descriptor).
+    ///
+    /// Returns `None` if there is no such method or the method has no code attribute.
+    ///
+    pub fn method_code(&self, sig: &str) -> Result<Option<CodeAttribute>, Error> {
+        let utf8_constpool = utf8_constants(self);
+        let method = match self.method_position(sig) {
+            Some(i) => &self.methods[i],
+            None => return Ok(None),
+        };
+
+        Ok(decode_attributes(&method.attributes, &utf8_constpool)?
+            .into_iter()
+            .find_map(|attr| match attr {
+                Attribute::Code(code_attr) => Some(code_attr),
+                _ => None,
+            }))
+    }
+
+    ///
+    /// Replaces the code attribute of the method with the given signature (method name followed by its descriptor).
+    ///
+    /// Returns an error if there is no such method or the method has no code attribute.
+    ///
+    pub fn set_method_code(&mut self, sig: &str, code: CodeAttribute) -> Result<(), Error> {
+        let utf8_constpool = utf8_constants(self);
+        let error = || Error::other(format!("method {} has no code attribute", sig));
+        let position = self.method_position(sig).ok_or_else(error)?;
+        let method = &mut self.methods[position];
+
+        let attr = method
+            .attributes
+            .iter_mut()
+            .find(|attr| {
+                utf8_constpool
+                    .get(&attr.attribute_name_index)
+                    .map(String::as_str)
+                    == Some("Code")
+            })
+            .ok_or_else(error)?;
+        *attr = code.encode(attr.attribute_name_index);
+
+        Ok(())
+    }
+
+    ///
+    /// Removes all attributes that only carry debug information,
+    /// i.e. source file, line number and local variable information, from the class and its methods.
+    ///
+    /// Constants only referenced by the removed attributes are kept in the constant pool.
+    ///
+    pub fn strip_debug(&mut self) -> Result<(), Error> {
+        let utf8_constpool = utf8_constants(self);
+        let is_debug = |attr: &AttributeInfo| {
+            utf8_constpool
+                .get(&attr.attribute_name_index)
+                .is_some_and(|name| DEBUG_ATTRIBUTES.contains(&name.as_str()))
+        };
+
+        self.attributes.retain(|attr| !is_debug(attr));
+        for method in &mut self.methods {
+            method.attributes.retain(|attr| !is_debug(attr));
+            for attr in &mut method.attributes {
+                if let Attribute::Code(mut code_attr) = decode_attribute(attr, &utf8_constpool)? {
+                    code_attr.attributes.retain(|attr| !is_debug(attr));
+                    *attr = code_attr.encode(attr.attribute_name_index);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Encodes a string as the modified UTF-8 of class files, which encodes NUL with two bytes
+/// and supplementary characters as surrogate pairs of three bytes each.
+///
+fn encode_modified_utf8(value: &str) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(value.len());
+    for unit in value.encode_utf16() {
+        match unit {
+            0x0001..=0x007f => bytes.push(unit as u8),
+            0x0000 | 0x0080..=0x07ff => {
+                bytes.extend([0xc0 | (unit >> 6) as u8, 0x80 | (unit & 0x3f) as u8])
+            }
+            _ => bytes.extend([
+                0xe0 | (unit >> 12) as u8,
+                0x80 | ((unit >> 6) & 0x3f) as u8,
+                0x80 | (unit & 0x3f) as u8,
+            ]),
+        }
+    }
+
+    bytes
+}
+
+///
+/// Decodes a modified UTF-8 string constant, replacing malformed sequences with U+FFFD.
+///
+fn decode_modified_utf8(bytes: &[u8]) -> String {
+    let mut units: Vec<u16> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let continuation = |offset: usize| {
+            bytes
+                .get(i + offset)
+                .filter(|byte| *byte & 0xc0 == 0x80)
+                .map(|byte| (byte & 0x3f) as u16)
+        };
+        let (unit, len) = match (bytes[i], continuation(1), continuation(2)) {
+            (byte @ 0x01..=0x7f, _, _) => (byte as u16, 1),
+            (byte @ 0xc0..=0xdf, Some(low), _) => (((byte & 0x1f) as u16) << 6 | low, 2),
+            (byte @ 0xe0..=0xef, Some(mid), Some(low)) => {
+                (((byte & 0x0f) as u16) << 12 | mid << 6 | low, 3)
+            }
+            _ => (0xfffd, 1),
+        };
+        units.push(unit);
+        i += len;
+    }
+
+    String::from_utf16_lossy(&units)
+}
+
 ///
 /// Extracts UTF-8 String and integer constants from class file constant pool definition.
 ///
@@ -187,12 +568,33 @@ pub fn constants(class: &ClassFile) -> BTreeMap<u16, ConstPoolValue> {
 
         match const_info {
             ConstPoolInfo::ConstUTF8 { length: _, bytes } => {
-                let content: &str = std::str::from_utf8(bytes).unwrap();
-                constpool.insert(i + 1, ConstPoolValue::UTF8String(content.to_string()));
+                constpool.insert(
+                    i + 1,
+                    ConstPoolValue::UTF8String(decode_modified_utf8(bytes)),
+                );
             }
             ConstPoolInfo::ConstInt { bytes } => {
                 constpool.insert(i + 1, ConstPoolValue::Integer(*bytes as i32));
             }
+            ConstPoolInfo::ConstFloat { bytes } => {
+                constpool.insert(i + 1, ConstPoolValue::Float(f32::from_bits(*bytes)));
+            }
+            ConstPoolInfo::ConstLong {
+                high_bytes,
+                low_bytes,
+            } => {
+                let value = ((*high_bytes as u64) << 32 | *low_bytes as u64) as i64;
+                constpool.insert(i + 1, ConstPoolValue::Long(value));
+            }
+            ConstPoolInfo::ConstFieldRef {
+                class_index,
+                name_and_type_index,
+            } => {
+                constpool.insert(
+                    i + 1,
+                    ConstPoolValue::FieldRef(*class_index, *name_and_type_index),
+                );
+            }
             ConstPoolInfo::ConstMethodRef {
                 class_index,
                 name_and_type_index,
@@ -221,6 +623,21 @@ pub fn constants(class: &ClassFile) -> BTreeMap<u16, ConstPoolValue> {
     constpool
 }
 
+///
+/// Extracts the UTF-8 String constants from the class file constant pool.
+///
+/// Returns a mapping of the constant pool index (1-based) to the corresponding string.
+///
+pub fn utf8_constants(class: &ClassFile) -> BTreeMap<u16, String> {
+    constants(class)
+        .into_iter()
+        .filter_map(|(k, v)| match v {
+            ConstPoolValue::UTF8String(value) => Some((k, value)),
+            _ => None,
+        })
+        .collect()
+}
+
 ///
 /// Parse method signature given the `NameAndType` constant reference of its method reference.
 ///
@@ -229,7 +646,7 @@ pub fn constants(class: &ClassFile) -> BTreeMap<u16, ConstPoolValue> {
 pub fn parse_method_signature(classinfo: &ClassFile, desc_ref: &u16) -> Result<String, Error> {
     let constpool = constants(classinfo);
     let mut signature = "".to_string();
-    let error = Error::new(ErrorKind::Other, "could not parse method reference");
+    let error = Error::other("could not parse method reference");
 
     if let ConstPoolValue::NameAndType(name_ref, type_ref) = constpool[desc_ref] {
         if let ConstPoolValue::UTF8String(method_name) = &constpool[&name_ref] {
@@ -247,6 +664,108 @@ pub fn parse_method_signature(classinfo: &ClassFile, desc_ref: &u16) -> Result<S
     Ok(signature)
 }
 
+///
+/// Looks up the binary name (e.g. `java/lang/Object`) of the class constant at the given constant pool index.
+///
+pub fn classname(classinfo: &ClassFile, index: u16) -> Option<String> {
+    let constpool = constants(classinfo);
+
+    match constpool.get(&index)? {
+        ConstPoolValue::Class(name_ref) => match constpool.get(name_ref)? {
+            ConstPoolValue::UTF8String(name) => Some(name.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+///
+/// Returns the binary name of the class defined by the class file.
+///
+pub fn this_class(classinfo: &ClassFile) -> String {
+    classname(classinfo, classinfo.this_class).unwrap_or_default()
+}
+
+///
+/// Returns the binary name of the superclass of the class defined by the class file,
+/// or `None` for `java/lang/Object`.
+///
+pub fn super_class(classinfo: &ClassFile) -> Option<String> {
+    classname(classinfo, classinfo.super_class)
+}
+
+///
+/// Resolves the method reference at the given constant pool index.
+///
+/// Returns the binary name of the referenced class and the method signature,
+/// or `None` if the index does not refer to a method reference.
+///
+pub fn methodref(classinfo: &ClassFile, index: u16) -> Option<(String, String)> {
+    match constants(classinfo).get(&index)? {
+        ConstPoolValue::MethodRef(class_ref, desc_ref) => Some((
+            classname(classinfo, *class_ref)?,
+            parse_method_signature(classinfo, desc_ref).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+///
+/// Resolves the field reference at the given constant pool index.
+///
+/// Returns the binary name of the referenced class, the field name and the field descriptor,
+/// or `None` if the index does not refer to a field reference.
+///
+pub fn fieldref(classinfo: &ClassFile, index: u16) -> Option<(String, String, String)> {
+    let constpool = constants(classinfo);
+    let utf8 = |index: &u16| match constpool.get(index)? {
+        ConstPoolValue::UTF8String(value) => Some(value.to_string()),
+        _ => None,
+    };
+
+    match constpool.get(&index)? {
+        ConstPoolValue::FieldRef(class_ref, desc_ref) => match constpool.get(desc_ref)? {
+            ConstPoolValue::NameAndType(name_ref, type_ref) => Some((
+                classname(classinfo, *class_ref)?,
+                utf8(name_ref)?,
+                utf8(type_ref)?,
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+///
+/// Extracts the names and descriptors of the non-static fields declared by the class, in declaration order.
+///
+pub fn instance_fields(classinfo: &ClassFile) -> Vec<(String, String)> {
+    fields(classinfo, false)
+}
+
+///
+/// Extracts the names and descriptors of the static fields declared by the class, in declaration order.
+///
+pub fn static_fields(classinfo: &ClassFile) -> Vec<(String, String)> {
+    fields(classinfo, true)
+}
+
+fn fields(classinfo: &ClassFile, statics: bool) -> Vec<(String, String)> {
+    let utf8_constpool = utf8_constants(classinfo);
+
+    classinfo
+        .fields
+        .iter()
+        .filter(|field| (field.access_flags & ACC_STATIC != 0) == statics)
+        .map(|field| {
+            (
+                utf8_constpool[&field.name_index].to_string(),
+                utf8_constpool[&field.descriptor_index].to_string(),
+            )
+        })
+        .collect()
+}
+
 ///
 /// Create mapping of method reference indices to corresponding method signature strings.
 ///
@@ -274,59 +793,166 @@ pub fn methodrefs(classinfo: &ClassFile) -> BiBTreeMap<u16, String> {
 /// - maximum number of local variables used by the method
 /// - vector of method bytecode
 ///
-pub fn codeblocks(class: &ClassFile) -> BTreeMap<String, BaliCode> {
+/// Returns an error if a `Code` or `LineNumberTable` attribute or a method descriptor is malformed.
+///
+pub fn codeblocks(class: &ClassFile) -> Result<BTreeMap<String, BaliCode>, Error> {
     let mut codeblocks: BTreeMap<String, BaliCode> = BTreeMap::new();
+    let utf8_constpool = utf8_constants(class);
 
-    let utf8_constpool: BTreeMap<u16, String> = constants(class)
-        .into_iter()
-        .filter(|(_, v)| matches!(v, ConstPoolValue::UTF8String(_)))
-        .map(|(k, v)| {
-            if let ConstPoolValue::UTF8String(value) = v {
-                (k, value)
-            } else {
-                (k, "".to_string())
+    for method_info in &class.methods {
+        let attributes = decode_attributes(&method_info.attributes, &utf8_constpool)?;
+
+        // abstract and native methods have no code attribute
+        let code_attr = attributes.into_iter().find_map(|attr| match attr {
+            Attribute::Code(code_attr) => Some(code_attr),
+            _ => None,
+        });
+
+        if let Some(code_attr) = code_attr {
+            let mut method_name: String = utf8_constpool[&method_info.name_index].to_string();
+            let method_desc: String = utf8_constpool[&method_info.descriptor_index].to_string();
+
+            let mut lines: Vec<(u16, u16)> = Vec::new();
+            let mut locals: Vec<LocalVariable> = Vec::new();
+            for attr in decode_attributes(&code_attr.attributes, &utf8_constpool)? {
+                match attr {
+                    Attribute::LineNumberTable(entries) => {
+                        lines.extend(entries.iter().map(|e| (e.start_pc, e.line_number)));
+                    }
+                    Attribute::LocalVariableTable(entries) => {
+                        locals.extend(entries.iter().map(|e| LocalVariable {
+                            start_pc: e.start_pc,
+                            length: e.length,
+                            index: e.index,
+                            name: utf8_constpool[&e.name_index].to_string(),
+                            descriptor: utf8_constpool[&e.descriptor_index].to_string(),
+                        }));
+                    }
+                    _ => {}
+                }
             }
-        })
-        .collect();
-
-    for i in 0..class.methods_count {
-        let method_info: &MethodInfo = &class.methods[i as usize];
-        let method_name_index: u16 = method_info.name_index;
-        let method_desc_index: u16 = method_info.descriptor_index;
-        let attr_info: &AttributeInfo = &method_info.attributes[0];
-        let attr_name_index: u16 = attr_info.attribute_name_index;
-
-        if utf8_constpool[&attr_name_index].eq("Code") {
-            let mut method_name: String = utf8_constpool[&method_name_index].to_string();
-            let method_desc: String = utf8_constpool[&method_desc_index].to_string();
-            let code_attr: CodeAttribute = Cursor::new(&attr_info.info).read_be().unwrap();
+            lines.sort();
+
             let code_info = BaliCode {
+                access_flags: method_info.access_flags,
                 max_stack: code_attr.max_stack,
                 max_locals: code_attr.max_locals,
-                argcount: parse_argcount(&method_desc),
+                argcount: parse_argcount(&method_desc, method_info.access_flags)?,
                 code: code_attr.code,
+                lines,
+                locals,
             };
             method_name.push_str(&method_desc);
             codeblocks.insert(method_name, code_info);
         }
     }
 
-    codeblocks
+    Ok(codeblocks)
 }
 
-fn parse_argcount(method_sig: &str) -> u16 {
-    let split: Vec<&str> = method_sig.split(|c| (c == '(') || (c == ')')).collect();
+///
+/// Looks up the name of the source file the class was compiled from.
+///
+/// Returns `None` if the class file has no well-formed `SourceFile` attribute, or an error if an attribute name
+/// is not a UTF-8 constant.
+///
+pub fn sourcefile(class: &ClassFile) -> Result<Option<String>, Error> {
+    let utf8_constpool = utf8_constants(class);
 
-    let mut argcount = 0;
-    let arglist = &split[1];
-    for c in arglist.chars() {
-        // count the number of arguments == letters between parentheses of a method signature
-        if c == '[' {
-            continue;
-        } else {
-            argcount += 1;
+    Ok(decode_attributes(&class.attributes, &utf8_constpool)?
+        .into_iter()
+        .find_map(|attr| match attr {
+            Attribute::SourceFile(index) => utf8_constpool.get(&index).cloned(),
+            _ => None,
+        }))
+}
+
+///
+/// Computes the number of argument slots a method pops from the stack when invoked,
+/// including `this` for instance methods.
+///
+fn parse_argcount(method_desc: &str, access_flags: u16) -> Result<u16, Error> {
+    let descriptor: MethodDescriptor = method_desc.parse()?;
+
+    if access_flags & ACC_STATIC != 0 {
+        Ok(descriptor.arg_slots())
+    } else {
+        Ok(descriptor.arg_slots() + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::runtime_class;
+
+    #[test]
+    fn modified_utf8() {
+        assert_eq!(encode_modified_utf8("a\0b"), vec![b'a', 0xc0, 0x80, b'b']);
+        assert_eq!(encode_modified_utf8("\u{e9}"), "\u{e9}".as_bytes());
+        assert_eq!(
+            encode_modified_utf8("\u{1f600}"),
+            vec![0xed, 0xa0, 0xbd, 0xed, 0xb8, 0x80]
+        );
+
+        for value in ["Sum", "a\0b", "\u{e9}\u{20ac}", "x\u{1f600}y", ""] {
+            assert_eq!(decode_modified_utf8(&encode_modified_utf8(value)), value);
         }
+        assert_eq!(decode_modified_utf8(&[b'a', 0xc0]), "a\u{fffd}");
+    }
+
+    #[test]
+    fn oversized_string_constant() {
+        let mut class = runtime_class();
+
+        assert!(class.insert_utf8(&"x".repeat(u16::MAX as usize)).is_ok());
+        assert!(class.insert_utf8(&"\0".repeat(40_000)).is_err());
+    }
+
+    #[test]
+    fn inserted_constants() {
+        let mut class = runtime_class();
+        let long = class
+            .insert_const(ConstPoolInfo::ConstLong {
+                high_bytes: 1,
+                low_bytes: 2,
+            })
+            .unwrap();
+        let utf8 = class.insert_utf8("inserted").unwrap();
+
+        assert_eq!(utf8, long + 2);
+        assert!(matches!(
+            constants(&class)[&long],
+            ConstPoolValue::Long(0x1_0000_0002)
+        ));
+        assert_eq!(utf8_constants(&class)[&utf8], "inserted");
     }
 
-    argcount
+    #[test]
+    fn write_read_round_trip() {
+        let mut class = runtime_class();
+        let index = class.insert_utf8("nul\0and\u{1f600}").unwrap();
+        let path =
+            std::env::temp_dir().join(format!("bake-roundtrip-{}.class", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        write_classfile(path, &mut class).unwrap();
+        let read = read_classfile(path);
+        std::fs::remove_file(path).unwrap();
+        let read = read.unwrap();
+
+        assert_eq!(utf8_constants(&read), utf8_constants(&class));
+        assert_eq!(utf8_constants(&read)[&index], "nul\0and\u{1f600}");
+        assert_eq!(this_class(&read), this_class(&class));
+
+        let (original, written) = (codeblocks(&class).unwrap(), codeblocks(&read).unwrap());
+        assert_eq!(
+            original.keys().collect::<Vec<_>>(),
+            written.keys().collect::<Vec<_>>()
+        );
+        for (sig, code) in &original {
+            assert_eq!(written[sig].code, code.code, "{}", sig);
+            assert_eq!(written[sig].argcount, code.argcount, "{}", sig);
+        }
+    }
 }
@@ -0,0 +1,237 @@
+use crate::memory::LUTENTRY;
+use crate::opcodes::{opmap, Op};
+use crate::uart::Protocol;
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::Error;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Name of the target profile used if no target is selected.
+pub const DEFAULT_TARGET: &str = "bali-v1";
+
+/// Built-in target profiles by name.
+const BUILTIN_TARGETS: [(&str, &str); 3] = [
+    ("bali-v1", include_str!("resources/targets/bali-v1.toml")),
+    (
+        "bali-v1-long",
+        include_str!("resources/targets/bali-v1-long.toml"),
+    ),
+    (
+        "bali-v1-nodiv",
+        include_str!("resources/targets/bali-v1-nodiv.toml"),
+    ),
+];
+
+/// Memory sizes of a Bali hardware revision.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemorySize {
+    /// Size of the program memory in bytes, holding the LUT and the method code.
+    pub program: usize,
+    /// Size of the evaluation stack in words.
+    pub stack: usize,
+    /// Maximum method call depth.
+    pub frames: usize,
+    /// Size of the local variable array memory in words, holding the local variables of all active method frames.
+    pub locals: usize,
+}
+
+/// Layout of a method LUT entry, packing the method address, argument count and local variable count
+/// into a big-endian word, from the most significant bits down.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LutFormat {
+    pub address_bits: u32,
+    pub args_bits: u32,
+    pub locals_bits: u32,
+}
+
+impl LutFormat {
+    ///
+    /// Packs the fields of a method LUT entry.
+    ///
+    /// Returns an error if a value exceeds the width of its field.
+    ///
+    pub fn pack(&self, addr: usize, args: usize, locals: usize) -> Result<[u8; LUTENTRY], Error> {
+        let fields = [
+            ("method address", addr, self.address_bits),
+            ("argument count", args, self.args_bits),
+            ("local variable count", locals, self.locals_bits),
+        ];
+
+        let mut entry: u32 = 0;
+        for (name, value, bits) in fields {
+            if value >= 1 << bits {
+                return Err(Error::other(format!(
+                    "{} {} exceeds the {} bit LUT field",
+                    name, value, bits
+                )));
+            }
+            entry = entry.checked_shl(bits).unwrap_or(0) | value as u32;
+        }
+
+        Ok(entry.to_be_bytes())
+    }
+
+    ///
+    /// Unpacks a method LUT entry into method address, argument count and local variable count.
+    ///
+    pub fn unpack(&self, entry: &[u8]) -> (usize, usize, usize) {
+        let entry = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]) as u64;
+        let field = |shift: u32, bits: u32| ((entry >> shift) & ((1 << bits) - 1)) as usize;
+
+        (
+            field(self.args_bits + self.locals_bits, self.address_bits),
+            field(self.locals_bits, self.args_bits),
+            field(0, self.locals_bits),
+        )
+    }
+}
+
+impl Default for LutFormat {
+    fn default() -> Self {
+        Target::default().lut
+    }
+}
+
+/// Hardware revision of the Bali processor a binary is generated for.
+///
+/// Every revision has to allocate arrays in static memory from address 0 upwards,
+/// since the prologue of `main` relies on its first allocation returning address 0,
+/// which holds the static fields of the runtime helpers and is reserved as the null reference.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Mnemonics of the instructions the processor executes, other instructions have to be lowered by `bake`.
+    pub opcodes: Vec<String>,
+    pub memory: MemorySize,
+    pub lut: LutFormat,
+    /// UART protocol spoken by the firmware.
+    #[serde(deserialize_with = "parse")]
+    pub protocol: Protocol,
+}
+
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+impl Target {
+    ///
+    /// Reads a target profile from its TOML description.
+    ///
+    /// Returns an error if the description is malformed, names an unknown instruction
+    /// or describes a LUT entry layout `bake` cannot generate.
+    ///
+    pub fn from_toml(text: &str) -> Result<Target, Error> {
+        let target: Target = toml::from_str(text).map_err(Error::other)?;
+
+        let opmap = opmap();
+        if let Some(mnemonic) = target
+            .opcodes
+            .iter()
+            .find(|mnemonic| !opmap.values().any(|op| op.mnemonic == **mnemonic))
+        {
+            return Err(Error::other(format!(
+                "target {}: unknown instruction {}",
+                target.name, mnemonic
+            )));
+        }
+
+        let lut = &target.lut;
+        if lut.address_bits + lut.args_bits + lut.locals_bits != 8 * LUTENTRY as u32 {
+            return Err(Error::other(format!(
+                "target {}: LUT entry fields do not add up to {} bits",
+                target.name,
+                8 * LUTENTRY
+            )));
+        }
+        // debug information and method calls address program memory with 16 bits
+        if lut.address_bits > 16 || target.memory.program > 1 << lut.address_bits {
+            return Err(Error::other(format!(
+                "target {}: program memory of {} bytes is not addressable with {} bit LUT addresses",
+                target.name, target.memory.program, lut.address_bits
+            )));
+        }
+
+        Ok(target)
+    }
+
+    ///
+    /// Looks up a built-in target profile by name.
+    ///
+    pub fn builtin(name: &str) -> Option<Target> {
+        BUILTIN_TARGETS
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, text)| Target::from_toml(text).unwrap())
+    }
+
+    ///
+    /// Lists the names of the built-in target profiles.
+    ///
+    pub fn builtin_names() -> Vec<&'static str> {
+        BUILTIN_TARGETS.iter().map(|(name, _)| *name).collect()
+    }
+
+    pub fn supports(&self, mnemonic: &str) -> bool {
+        self.opcodes.iter().any(|supported| supported == mnemonic)
+    }
+
+    ///
+    /// Creates the opcode mapping for the target, with the instructions it executes marked as supported.
+    ///
+    pub fn opmap(&self) -> HashMap<u8, Op> {
+        let mut opmap = opmap();
+        for op in opmap.values_mut() {
+            op.supported_on_bali = self.supports(&op.mnemonic);
+        }
+
+        opmap
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::builtin(DEFAULT_TARGET).unwrap()
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    ///
+    /// Selects a built-in target profile by name, or reads a target profile from a TOML file.
+    ///
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(target) = Target::builtin(value) {
+            return Ok(target);
+        }
+
+        if !Path::new(value).is_file() {
+            return Err(format!(
+                "unknown target '{}', expected a TOML file or one of {}",
+                value,
+                Target::builtin_names().join(", ")
+            ));
+        }
+
+        std::fs::read_to_string(value)
+            .and_then(|text| Target::from_toml(&text))
+            .map_err(|err| err.to_string())
+    }
+}
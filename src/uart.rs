@@ -1,8 +1,85 @@
-use serialport::{DataBits, FlowControl, Parity, SerialPort, StopBits};
+use serialport::{
+    DataBits, FlowControl, Parity, SerialPort, SerialPortInfo, SerialPortType, StopBits,
+};
 
-use std::time::Duration;
+use std::fmt;
+use std::io::{Error, ErrorKind, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-pub fn open_serial(port_id: &str) -> Box<dyn SerialPort> {
+///
+/// Lists the serial ports available on this machine.
+///
+/// Returns an empty list if the ports could not be enumerated.
+///
+pub fn available_ports() -> Vec<SerialPortInfo> {
+    serialport::available_ports().unwrap_or_default()
+}
+
+///
+/// Formats a port for the `ports` listing, including USB VID/PID, manufacturer and serial number.
+///
+pub fn portstring(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(info) => format!(
+            "{:20} USB {:04x}:{:04x}  {}  {}",
+            port.port_name,
+            info.vid,
+            info.pid,
+            info.manufacturer.as_deref().unwrap_or("-"),
+            info.serial_number.as_deref().unwrap_or("-"),
+        ),
+        SerialPortType::PciPort => format!("{:20} PCI", port.port_name),
+        SerialPortType::BluetoothPort => format!("{:20} Bluetooth", port.port_name),
+        SerialPortType::Unknown => format!("{:20} unknown", port.port_name),
+    }
+}
+
+///
+/// Selects the serial device whose USB VID/PID matches the given filter.
+///
+/// Returns the port name if exactly one port matches, otherwise returns an error listing the candidates.
+/// At least one of VID and PID has to be given, so an unrelated USB serial device is never selected.
+///
+pub fn find_device(vid: Option<u16>, pid: Option<u16>) -> Result<String, Error> {
+    if vid.is_none() && pid.is_none() {
+        let names: Vec<String> = available_ports()
+            .into_iter()
+            .map(|port| port.port_name)
+            .collect();
+        return Err(Error::other(format!(
+            "no device, VID or PID given, available serial ports: {}",
+            match names.is_empty() {
+                true => "none".to_string(),
+                false => names.join(", "),
+            }
+        )));
+    }
+
+    let matches: Vec<SerialPortInfo> = available_ports()
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(info) => {
+                vid.is_none_or(|v| v == info.vid) && pid.is_none_or(|p| p == info.pid)
+            }
+            _ => false,
+        })
+        .collect();
+
+    match matches.len() {
+        0 => Err(Error::other("no serial device matches the VID/PID filter")),
+        1 => Ok(matches[0].port_name.clone()),
+        _ => {
+            let names: Vec<&str> = matches.iter().map(|p| p.port_name.as_str()).collect();
+            Err(Error::other(format!(
+                "multiple serial devices match the VID/PID filter: {}",
+                names.join(", ")
+            )))
+        }
+    }
+}
+
+pub fn open_serial(port_id: &str) -> Result<Box<dyn SerialPort>, Error> {
     serialport::new(port_id, 9600)
         .data_bits(DataBits::Eight)
         .flow_control(FlowControl::None)
@@ -10,52 +87,476 @@ pub fn open_serial(port_id: &str) -> Box<dyn SerialPort> {
         .stop_bits(StopBits::One)
         .timeout(Duration::from_millis(10000))
         .open()
-        .expect("could not open serial port")
+        .map_err(|err| Error::other(format!("could not open serial port {}: {}", port_id, err)))
+}
+
+/// Byte order of multi-byte values transferred over the UART connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// Description of the UART protocol spoken by a Bali firmware revision.
+#[derive(Clone, Debug)]
+pub struct Protocol {
+    /// Width of the program length prefix in bytes.
+    pub length_width: usize,
+    /// Byte order of the program length prefix.
+    pub length_order: Endianness,
+    /// Width of the cycle count reported after execution in bytes.
+    pub cycles_width: usize,
+    /// Byte order of the cycle count and of read back result words.
+    pub result_order: Endianness,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol {
+            length_width: 1,
+            length_order: Endianness::Little,
+            cycles_width: 8,
+            result_order: Endianness::Little,
+        }
+    }
+}
+
+fn parse_field(value: &str, widths: &[usize]) -> Result<(usize, Endianness), String> {
+    let (bits, order) = if let Some(bits) = value.strip_suffix("le") {
+        (bits, Endianness::Little)
+    } else if let Some(bits) = value.strip_suffix("be") {
+        (bits, Endianness::Big)
+    } else {
+        (value, Endianness::Little)
+    };
+
+    match bits.parse::<usize>() {
+        Ok(bits) if widths.contains(&bits) => Ok((bits / 8, order)),
+        _ => Err(format!("invalid protocol field width '{}'", value)),
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut protocol = Protocol::default();
+
+        for field in value.split(',') {
+            match field.split_once('=') {
+                Some(("length", spec)) => {
+                    (protocol.length_width, protocol.length_order) = parse_field(spec, &[8, 16])?;
+                }
+                Some(("cycles", spec)) => {
+                    (protocol.cycles_width, protocol.result_order) = parse_field(spec, &[32, 64])?;
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid protocol field '{}', expected length=BITS[le|be] or cycles=BITS[le|be]",
+                        field
+                    ))
+                }
+            }
+        }
+
+        Ok(protocol)
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let order = |o: Endianness| if o == Endianness::Little { "le" } else { "be" };
+        write!(
+            f,
+            "length={}{},cycles={}{}",
+            self.length_width * 8,
+            order(self.length_order),
+            self.cycles_width * 8,
+            order(self.result_order)
+        )
+    }
+}
+
+fn encode(value: u64, width: usize, order: Endianness) -> Vec<u8> {
+    let bytes = value.to_le_bytes()[..width].to_vec();
+    match order {
+        Endianness::Little => bytes,
+        Endianness::Big => bytes.into_iter().rev().collect(),
+    }
+}
+
+fn decode(bytes: &[u8], order: Endianness) -> u64 {
+    let fold = |value: u64, byte: &u8| (value << 8) | *byte as u64;
+    match order {
+        Endianness::Little => bytes.iter().rev().fold(0, fold),
+        Endianness::Big => bytes.iter().fold(0, fold),
+    }
+}
+
+///
+/// Reads the given number of bytes from the device, appending them to the log of received bytes.
+///
+/// Returns an error stating how many bytes arrived if the device sends fewer bytes than expected.
+///
+fn receive(
+    port: &mut Box<dyn SerialPort>,
+    count: usize,
+    received: &mut Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    let mut buffer: Vec<u8> = vec![0; count];
+    let mut filled = 0;
+
+    while filled < count {
+        match port.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                received.extend_from_slice(&buffer[filled..filled + n]);
+                filled += n;
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    if filled < count {
+        return Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("expected {} bytes from device, received {}", count, filled),
+        ));
+    }
+
+    Ok(buffer)
 }
 
+///
+/// Transfers a Bali binary to the device, waiting for the device to acknowledge each byte.
+///
+/// All bytes received from the device are appended to `received`.
+///
+fn upload(
+    port: &mut Box<dyn SerialPort>,
+    bin: &[u8],
+    protocol: &Protocol,
+    received: &mut Vec<u8>,
+) -> Result<(), std::io::Error> {
+    if bin.len() >= 1 << (8 * protocol.length_width) {
+        return Err(Error::other(format!(
+            "binary of {} bytes exceeds the {} bit program length",
+            bin.len(),
+            8 * protocol.length_width
+        )));
+    }
+
+    let memlen = encode(
+        bin.len() as u64,
+        protocol.length_width,
+        protocol.length_order,
+    );
+
+    for byte in memlen.iter().chain(bin) {
+        port.write_all(&[*byte])?;
+        receive(port, 1, received)?;
+    }
+
+    Ok(())
+}
+
+///
+/// Transfers a Bali binary to the device and waits for the program to finish.
+///
+/// Returns the cycle count reported by the device, reading exactly the bytes of the cycle count described
+/// by the protocol. All bytes received from the device are appended to `received`.
+///
 pub fn binwrite(
     port: &mut Box<dyn SerialPort>,
     bin: &[u8],
-    long: bool,
+    protocol: &Protocol,
+    received: &mut Vec<u8>,
 ) -> Result<u64, std::io::Error> {
-    if !long {
-        let memlen: Vec<u8> = vec![bin.len().try_into().unwrap()];
-        port.write_all(&memlen)?;
-        let mut response: Vec<u8> = vec![0; 1];
-        port.read_exact(&mut response)?;
-    } else {
-        let binlen: u16 = bin.len().try_into().unwrap();
+    upload(port, bin, protocol, received)?;
 
-        let mut memlen: Vec<u8> = Vec::<u8>::new();
-        memlen.push((binlen & 0xff) as u8);
-        memlen.push((binlen >> 8) as u8);
+    let cycles = receive(port, protocol.cycles_width, received).map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!(
+                "incomplete cycle count ({}), check protocol {}",
+                err, protocol
+            ),
+        )
+    })?;
 
-        for byte in memlen {
-            port.write_all(&[byte])?;
-            let mut response: Vec<u8> = vec![0; 1];
-            port.read_exact(&mut response)?;
+    Ok(decode(&cycles, protocol.result_order))
+}
+
+/// Byte sequence that ends monitoring, given either as text or as hex bytes prefixed with `0x`.
+#[derive(Debug)]
+pub struct Terminator(pub Vec<u8>);
+
+impl FromStr for Terminator {
+    type Err = hex::FromHexError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("0x") {
+            Some(bytes) => Ok(Terminator(hex::decode(bytes)?)),
+            None => Ok(Terminator(value.as_bytes().to_vec())),
+        }
+    }
+}
+
+///
+/// Appends a received chunk to the bytes kept for detecting the terminator and returns the number of leading
+/// chunk bytes up to and including the terminator, if the chunk completes it.
+///
+/// Only the bytes which may start a terminator spanning the next chunk are kept.
+///
+fn terminator_end(received: &mut Vec<u8>, chunk: &[u8], terminator: &[u8]) -> Option<usize> {
+    if terminator.is_empty() {
+        return None;
+    }
+
+    let kept = received.len();
+    received.extend_from_slice(chunk);
+    if let Some(start) = received
+        .windows(terminator.len())
+        .position(|window| window == terminator)
+    {
+        return Some(start + terminator.len() - kept);
+    }
+    let stale = received.len().saturating_sub(terminator.len() - 1);
+    received.drain(..stale);
+
+    None
+}
+
+///
+/// Streams bytes received from the device to the output, each line prefixed with the time since the start.
+///
+/// In hex mode, every received chunk is printed as one line of hex bytes.
+/// Runs until the terminator sequence has been received or the process is interrupted,
+/// bytes following the terminator are not printed.
+///
+pub fn monitor(
+    port: &mut Box<dyn SerialPort>,
+    out: &mut impl Write,
+    hex: bool,
+    terminator: Option<&Terminator>,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let mut buffer: Vec<u8> = vec![0; 256];
+    let mut received: Vec<u8> = Vec::new();
+    let mut line_start = true;
+
+    loop {
+        let count = match port.read(&mut buffer) {
+            Ok(count) => count,
+            Err(err) if err.kind() == ErrorKind::TimedOut => continue,
+            Err(err) => return Err(err),
+        };
+        let timestamp = start.elapsed().as_secs_f64();
+
+        let end = terminator.and_then(|Terminator(terminator)| {
+            terminator_end(&mut received, &buffer[..count], terminator)
+        });
+        let chunk = &buffer[..end.unwrap_or(count)];
+
+        if hex {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "[{:10.3}] {}", timestamp, bytes.join(" "))?;
+        } else {
+            for byte in chunk {
+                if line_start {
+                    write!(out, "[{:10.3}] ", timestamp)?;
+                }
+                out.write_all(&[*byte])?;
+                line_start = *byte == b'\n';
+            }
+        }
+
+        if end.is_some() {
+            if !hex && !line_start {
+                writeln!(out)?;
+            }
+            out.flush()?;
+            return Ok(());
+        }
+        out.flush()?;
+    }
+}
+
+/// Result data to read back from the Bali device after execution.
+#[derive(Debug)]
+pub enum Readback {
+    /// Value on top of the evaluation stack.
+    TopOfStack,
+    /// Range of static memory words, given by start address and word count.
+    Memory(u16, u16),
+    /// Stream of words the program wrote to its output buffer.
+    Output,
+}
+
+impl FromStr for Readback {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = value.split(':').collect();
+        let number = |s: &str| match s.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => s.parse::<u16>(),
+        };
+
+        match parts[..] {
+            ["tos"] => Ok(Readback::TopOfStack),
+            ["output"] => Ok(Readback::Output),
+            ["mem", start, count] => Ok(Readback::Memory(
+                number(start).map_err(|e| e.to_string())?,
+                number(count).map_err(|e| e.to_string())?,
+            )),
+            _ => Err(format!(
+                "invalid readback '{}', expected tos, output or mem:START:COUNT",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Readback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Readback::TopOfStack => write!(f, "tos"),
+            Readback::Memory(start, count) => write!(f, "mem:{:#x}:{}", start, count),
+            Readback::Output => write!(f, "output"),
+        }
+    }
+}
+
+fn read_words(
+    port: &mut Box<dyn SerialPort>,
+    count: usize,
+    protocol: &Protocol,
+    received: &mut Vec<u8>,
+) -> Result<Vec<i32>, Error> {
+    let bytes = receive(port, 4 * count, received)?;
+
+    Ok(bytes
+        .chunks(4)
+        .map(|word| decode(word, protocol.result_order) as i32)
+        .collect())
+}
+
+///
+/// Requests result data from the Bali device after the cycle count has been received.
+///
+/// The request consists of a command byte (`0x01` top of stack, `0x02` static memory, `0x03` output)
+/// followed by the 16-bit start address and word count for memory requests, low byte first.
+/// The device answers with 32-bit words in the result byte order of the protocol,
+/// preceded by a 16-bit word count (low byte first) for output requests.
+///
+/// Returns the words read from the device. All bytes received from the device are appended to `received`.
+///
+pub fn readback(
+    port: &mut Box<dyn SerialPort>,
+    request: &Readback,
+    protocol: &Protocol,
+    received: &mut Vec<u8>,
+) -> Result<Vec<i32>, Error> {
+    match request {
+        Readback::TopOfStack => {
+            port.write_all(&[0x01])?;
+            read_words(port, 1, protocol, received)
+        }
+        Readback::Memory(start, count) => {
+            let mut command: Vec<u8> = vec![0x02];
+            command.extend_from_slice(&start.to_le_bytes());
+            command.extend_from_slice(&count.to_le_bytes());
+            port.write_all(&command)?;
+            read_words(port, *count as usize, protocol, received)
+        }
+        Readback::Output => {
+            port.write_all(&[0x03])?;
+            let count = receive(port, 2, received)?;
+            read_words(
+                port,
+                decode(&count, Endianness::Little) as usize,
+                protocol,
+                received,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_fields() {
+        let protocol: Protocol = "length=16be,cycles=32".parse().unwrap();
+
+        assert_eq!(protocol.length_width, 2);
+        assert_eq!(protocol.length_order, Endianness::Big);
+        assert_eq!(protocol.cycles_width, 4);
+        assert_eq!(protocol.result_order, Endianness::Little);
+        assert_eq!(protocol.to_string(), "length=16be,cycles=32le");
+    }
+
+    #[test]
+    fn protocol_defaults_and_round_trip() {
+        let protocol: Protocol = "cycles=64be".parse().unwrap();
+
+        assert_eq!(protocol.length_width, 1);
+        assert_eq!(protocol.length_order, Endianness::Little);
+        let reparsed: Protocol = protocol.to_string().parse().unwrap();
+        assert_eq!(reparsed.to_string(), protocol.to_string());
+    }
+
+    #[test]
+    fn invalid_protocols() {
+        for spec in [
+            "length=24",
+            "cycles=16le",
+            "length=16xe",
+            "width=8",
+            "length",
+        ] {
+            assert!(spec.parse::<Protocol>().is_err(), "{}", spec);
         }
     }
 
-    for byte in bin {
-        let to_write: Vec<u8> = vec![*byte];
-        port.write_all(&to_write)?;
+    #[test]
+    fn encode_decode() {
+        assert_eq!(encode(0x1234, 2, Endianness::Little), vec![0x34, 0x12]);
+        assert_eq!(encode(0x1234, 2, Endianness::Big), vec![0x12, 0x34]);
+        assert_eq!(decode(&[0x34, 0x12], Endianness::Little), 0x1234);
+        assert_eq!(decode(&[0x12, 0x34], Endianness::Big), 0x1234);
 
-        let mut response: Vec<u8> = vec![0; 1];
-        port.read_exact(&mut response)?;
+        for order in [Endianness::Little, Endianness::Big] {
+            for width in [1, 2, 4, 8] {
+                let value = 0x0123_4567_89ab_cdef_u64 >> (64 - 8 * width);
+                assert_eq!(decode(&encode(value, width, order), order), value);
+            }
+        }
     }
 
-    let mut cycles: Vec<u8> = vec![0; 8];
-    port.read_exact(&mut cycles)?;
+    #[test]
+    fn terminator_within_chunk() {
+        let mut received = Vec::new();
+
+        assert_eq!(terminator_end(&mut received, b"abc", b"END"), None);
+        assert_eq!(terminator_end(&mut received, b"xENDyz", b"END"), Some(4));
+    }
 
-    let turnaround: u64 = ((cycles[7] as u64) << 56)
-        + ((cycles[6] as u64) << 48)
-        + ((cycles[5] as u64) << 40)
-        + ((cycles[4] as u64) << 32)
-        + ((cycles[3] as u64) << 24)
-        + ((cycles[2] as u64) << 16)
-        + ((cycles[1] as u64) << 8)
-        + (cycles[0] as u64);
+    #[test]
+    fn terminator_across_chunks() {
+        let mut received = Vec::new();
 
-    Ok(turnaround)
+        assert_eq!(terminator_end(&mut received, b"abcE", b"END"), None);
+        assert_eq!(received, b"cE");
+        assert_eq!(terminator_end(&mut received, b"N", b"END"), None);
+        assert_eq!(terminator_end(&mut received, b"Dtail", b"END"), Some(1));
+    }
+
+    #[test]
+    fn empty_terminator() {
+        assert_eq!(terminator_end(&mut Vec::new(), b"abc", b""), None);
+    }
 }
@@ -0,0 +1,187 @@
+use crate::depth::{memory_usage, Usage};
+use crate::emulate::{lower_float, FloatMode};
+use crate::ir::decode;
+use crate::memory::{binarygen, lower, Options};
+use crate::opcodes::opmap;
+use crate::structs::{constants, ClassFile};
+
+/// Compatibility issue of a method with the Bali processor, or a note on its translation.
+pub struct Diagnostic {
+    pub method: String,
+    pub message: String,
+}
+
+/// Result of checking a class set for compatibility with the Bali processor.
+#[derive(Default)]
+pub struct Report {
+    /// Issues preventing the conversion of the class set to a Bali binary.
+    pub issues: Vec<Diagnostic>,
+    /// Translations changing the behavior of the program, e.g. `float` operations converted to fixed-point,
+    /// and the worst-case memory usage of the program.
+    pub notes: Vec<Diagnostic>,
+    /// Properties of the program that may make it fail on Bali, e.g. recursion.
+    pub warnings: Vec<Diagnostic>,
+}
+
+fn usage_chain(usage: &Usage) -> String {
+    usage.chain.join(" -> ")
+}
+
+///
+/// Checks the methods of a class set for compatibility with the target revision of the Bali processor.
+///
+/// Returns the issues found, which are empty if the classes can be converted to a Bali binary,
+/// along with notes on the translation of the methods. The worst-case depth of the call stack, evaluation stack
+/// and LVA memories is reported as well, and is an issue if it exceeds the memory sizes of the target.
+///
+pub fn verify(classes: &[ClassFile], options: &Options) -> Report {
+    let program = match lower(classes, options) {
+        Ok(program) => program,
+        Err(err) => {
+            return Report {
+                issues: vec![Diagnostic {
+                    method: String::new(),
+                    message: err.to_string(),
+                }],
+                ..Default::default()
+            }
+        }
+    };
+
+    let opmap = opmap();
+    let target = &options.target;
+    let mut report = Report::default();
+    for method in &program.methods {
+        if options.float == FloatMode::Fixed16 && method.class < classes.len() {
+            let constpool = constants(&classes[method.class]);
+            let instrs = decode(&method.code.code, &method.code.lines).unwrap_or_default();
+            for instr in instrs {
+                let mnemonic = opmap[&instr.opcode].mnemonic.as_str();
+                // loads, stores and returns move fixed-point values unchanged
+                let moves = mnemonic.starts_with("fload")
+                    || mnemonic.starts_with("fstore")
+                    || matches!(mnemonic, "faload" | "fastore" | "freturn");
+                if !moves
+                    && matches!(
+                        lower_float(mnemonic, &instr, &constpool, options.float),
+                        Some(Ok(_))
+                    )
+                {
+                    let line = instr
+                        .line
+                        .map(|line| format!(" (line {})", line))
+                        .unwrap_or_default();
+                    report.notes.push(Diagnostic {
+                        method: method.name.to_string(),
+                        message: format!(
+                            "{} at pc {}{} converted to Q16.16 fixed-point",
+                            mnemonic,
+                            instr.pc.unwrap_or_default(),
+                            line
+                        ),
+                    });
+                }
+            }
+        }
+
+        if !method.savings.is_empty() {
+            report.notes.push(Diagnostic {
+                method: method.name.to_string(),
+                message: format!(
+                    "peephole optimisation saves {} bytes and ~{} cycles",
+                    method.savings.bytes, method.savings.cycles
+                ),
+            });
+        }
+
+        let errors = [
+            method
+                .code
+                .validate(&target.lut)
+                .err()
+                .map(|err| err.to_string()),
+            method.instrs.as_ref().err().map(|err| err.to_string()),
+        ];
+        for message in errors.into_iter().flatten() {
+            report.issues.push(Diagnostic {
+                method: method.name.to_string(),
+                message,
+            });
+        }
+    }
+
+    if report.issues.is_empty() {
+        match memory_usage(&program.methods) {
+            Ok((usage, cycles)) => {
+                for cycle in cycles {
+                    report.warnings.push(Diagnostic {
+                        method: String::new(),
+                        message: format!(
+                            "recursion {}, the memory usage only covers a single pass",
+                            cycle.join(" -> ")
+                        ),
+                    });
+                }
+
+                let memories = [
+                    ("call depth", "frames", &usage.frames, target.memory.frames),
+                    (
+                        "evaluation stack",
+                        "words",
+                        &usage.stack,
+                        target.memory.stack,
+                    ),
+                    (
+                        "local variables",
+                        "words",
+                        &usage.locals,
+                        target.memory.locals,
+                    ),
+                ];
+                for (memory, unit, usage, size) in memories {
+                    report.notes.push(Diagnostic {
+                        method: String::new(),
+                        message: format!(
+                            "{}: {} of {} {} ({})",
+                            memory,
+                            usage.value,
+                            size,
+                            unit,
+                            usage_chain(usage)
+                        ),
+                    });
+                    if usage.value > size {
+                        report.issues.push(Diagnostic {
+                            method: String::new(),
+                            message: format!(
+                                "{} of {} exceeds the {} {} of target {} ({})",
+                                memory,
+                                usage.value,
+                                size,
+                                unit,
+                                target.name,
+                                usage_chain(usage)
+                            ),
+                        });
+                    }
+                }
+            }
+            Err(err) => report.issues.push(Diagnostic {
+                method: String::new(),
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    // the size of the binary is only known once all methods are lowered
+    if report.issues.is_empty() {
+        if let Err(err) = binarygen(classes, options) {
+            report.issues.push(Diagnostic {
+                method: String::new(),
+                message: err.to_string(),
+            });
+        }
+    }
+
+    report
+}